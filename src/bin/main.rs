@@ -3,73 +3,512 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::time::Duration;
 
-use clickward::{Deployment, KeeperClient};
+use anyhow::{anyhow, bail};
+use clickward::config::{
+    ConfigFormat, DiskConfig, PolicyConfig, ReplicaMacroScheme, ShardWeight,
+};
+use clickward::{
+    deployment_dir, named_deployments, parse_id_set, ClickwardError,
+    ClickwardMetadata, Deployment, DeploymentConfig, DeploymentSpec,
+    KeeperClient, KeeperId, MembershipChange, NodeRef, PortKind, RunningCounts,
+    ServerId, StopOutcome, TeardownReport, CLICKWARD_META_FILENAME,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Cli {
+    /// How read-only commands print their results
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// The output format of read-only commands
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human readable output
+    Text,
+    /// Pretty printed JSON
+    Json,
+}
+
+/// Print `value` in the given output format
+fn print_output<T: Serialize + Debug>(
+    format: OutputFormat,
+    value: &T,
+) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Text => println!("{value:#?}"),
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(value)?)
+        }
+    }
+    Ok(())
+}
+
+/// What `show` prints for a generated deployment
+#[derive(Debug, Serialize, Deserialize)]
+struct ShowOutput {
+    path: Utf8PathBuf,
+    cluster: String,
+    metadata: ClickwardMetadata,
+    running: RunningCounts,
+}
+
+/// What `show` prints if there is no deployment directly below the path
+#[derive(Debug, Serialize, Deserialize)]
+struct NamedDeploymentsOutput {
+    named_deployments: Vec<String>,
+}
+
+/// A port bound by a node, as printed by `ports`
+#[derive(Debug, Serialize, Deserialize)]
+struct PortOutput {
+    port: u16,
+    kind: PortKind,
+    node: NodeRef,
+}
+
+/// Arguments that select the deployment to operate on
+#[derive(Args, Debug)]
+struct DeploymentArgs {
+    /// Root path of all configuration
+    #[arg(short, long)]
+    path: Utf8PathBuf,
+
+    /// Name of a deployment, for keeping several under one path
+    #[arg(long)]
+    name: Option<String>,
+}
+
+/// Arguments of commands that start nodes
+#[derive(Args, Debug)]
+struct SpawnArgs {
+    /// Print the command line of every node that is started
+    #[arg(long)]
+    print_commands: bool,
+}
+
+impl SpawnArgs {
+    fn apply(&self, d: &mut Deployment) {
+        d.set_print_commands(self.print_commands);
+    }
+}
+
+/// Arguments of commands that generate configs or wait on nodes
+#[derive(Args, Debug)]
+struct TimingArgs {
+    /// Print how long generating configs, starting nodes and waiting for
+    /// them to become ready take
+    #[arg(long)]
+    timings: bool,
+}
+
+impl TimingArgs {
+    fn apply(&self, d: &mut Deployment) {
+        d.set_timings(self.timings);
+    }
+}
+
+impl DeploymentArgs {
+    fn config(self) -> anyhow::Result<DeploymentConfig> {
+        match self.name {
+            // Each named deployment is its own cluster
+            Some(name) => DeploymentConfig::new_named_with_default_ports(
+                self.path, &name, &name,
+            ),
+            None => {
+                Ok(DeploymentConfig::new_with_default_ports(self.path, CLUSTER))
+            }
+        }
+    }
+
+    fn load(self) -> anyhow::Result<Deployment> {
+        Ok(Deployment::new(self.config()?))
+    }
+}
+
 #[derive(Subcommand, Debug)]
+// Only one command is ever parsed, so the size of `GenConfig` doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Generate configuration for our clickhouse and keeper clusters
+    #[command(group(ArgGroup::new("settings").multiple(true)))]
     GenConfig {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        timing: TimingArgs,
+
+        /// Read the deployment from a JSON or TOML spec file. Other flags
+        /// override the values in the spec.
+        #[arg(long, group = "settings")]
+        from_spec: Option<Utf8PathBuf>,
+
+        /// Rewrite the configs of an existing deployment from its metadata,
+        /// keeping its node ids, settings and data. No other settings may be
+        /// given.
+        #[arg(long, conflicts_with = "settings")]
+        replace: bool,
 
         /// Number of clickhouse keepers
-        #[arg(long)]
-        num_keepers: u64,
+        #[arg(
+            long,
+            group = "settings",
+            required_unless_present_any = ["from_spec", "replace"]
+        )]
+        num_keepers: Option<u64>,
 
         /// Number of clickhouse replicas
-        #[arg(long)]
-        num_replicas: u64,
+        #[arg(
+            long,
+            group = "settings",
+            required_unless_present_any = ["from_spec", "replace"]
+        )]
+        num_replicas: Option<u64>,
+
+        /// File format of the generated configs [default: xml]
+        #[arg(long, group = "settings")]
+        format: Option<ConfigFormat>,
+
+        /// Enable asynchronous inserts in the default profile
+        #[arg(long, group = "settings")]
+        async_insert: bool,
+
+        /// Override the async insert busy timeout in milliseconds
+        #[arg(long, group = "settings", requires = "async_insert")]
+        async_insert_busy_timeout_ms: Option<u64>,
+
+        /// Override the maximum bytes buffered by async inserts
+        #[arg(long, group = "settings", requires = "async_insert")]
+        async_insert_max_data_size: Option<u64>,
+
+        /// Add a disk as `<name>=<path>`, with the path relative to each
+        /// server's directory. May be repeated.
+        #[arg(long = "disk", group = "settings")]
+        disks: Vec<DiskConfig>,
+
+        /// Add a storage policy as
+        /// `<name>=<volume>:<disk>[+<disk>...][,<volume>:<disk>...]`.
+        /// May be repeated.
+        #[arg(long = "policy", group = "settings")]
+        policies: Vec<PolicyConfig>,
+
+        /// Don't define the `opentelemetry_span_log` table in server configs.
+        /// Only use this with ClickHouse versions that create it themselves.
+        #[arg(long, group = "settings")]
+        no_span_log: bool,
+
+        /// Give each node its own IPv4 loopback address (127.1.x.y for
+        /// keepers, 127.2.x.y for servers) instead of sharing `::1`. Outside
+        /// of Linux these addresses must be configured as loopback aliases.
+        #[arg(long, group = "settings")]
+        loopback_per_node: bool,
+
+        /// An address for every node to listen on. May be given multiple
+        /// times, e.g. `--listen-host :: --listen-host 0.0.0.0` for
+        /// dual-stack. Defaults to each node's own address.
+        #[arg(long = "listen-host", value_name = "ADDR", group = "settings")]
+        listen_hosts: Vec<String>,
+
+        /// Let nodes start even if some listen addresses can't be bound
+        #[arg(long, group = "settings")]
+        listen_try: bool,
+
+        /// Split clickhouse servers round-robin across this many shards
+        #[arg(long, group = "settings")]
+        num_shards: Option<u64>,
+
+        /// Weight a shard for `Distributed` table inserts as
+        /// `<shard>=<weight>`. May be given multiple times.
+        #[arg(long = "shard-weight", group = "settings")]
+        shard_weights: Vec<ShardWeight>,
+
+        /// How to number the `{replica}` macro: `global` uses the server id,
+        /// `per-shard` the server's index within its shard
+        #[arg(long, group = "settings")]
+        replica_macro_scheme: Option<ReplicaMacroScheme>,
+
+        /// Cap the memory of each clickhouse server, in bytes
+        #[arg(long, group = "settings")]
+        max_memory: Option<u64>,
+
+        /// Number of merge and mutation threads of each clickhouse server
+        #[arg(long, group = "settings")]
+        background_pool_size: Option<u64>,
+
+        /// Size of the mark cache of each clickhouse server, in bytes
+        #[arg(long, group = "settings")]
+        mark_cache_size: Option<u64>,
+
+        /// Maximum size of the global thread pool of each clickhouse server
+        #[arg(long, group = "settings")]
+        max_thread_pool_size: Option<u64>,
+
+        /// Maximum size of the IO thread pool of each clickhouse server
+        #[arg(long, group = "settings")]
+        max_io_thread_pool_size: Option<u64>,
+
+        /// Lock the executable of each clickhouse server in memory
+        #[arg(long, group = "settings")]
+        mlock_executable: bool,
+
+        /// Serve Prometheus metrics from each clickhouse server on its own
+        /// port, starting at 25001
+        #[arg(long, group = "settings")]
+        prometheus: bool,
+
+        /// The http path of the Prometheus endpoint
+        #[arg(
+            long,
+            default_value = "/metrics",
+            group = "settings",
+            requires = "prometheus"
+        )]
+        prometheus_endpoint: String,
+
+        /// Seconds clickhouse servers keep idle http connections open
+        #[arg(long, group = "settings")]
+        keep_alive_timeout: Option<u32>,
+
+        /// Whether keepers reply to writes before followers acknowledge them
+        #[arg(long, group = "settings")]
+        keeper_async_replication: Option<bool>,
+
+        /// Whether keeper reads go through raft
+        #[arg(long, group = "settings")]
+        keeper_quorum_reads: Option<bool>,
+
+        /// Whether keepers fsync their raft log on every write. Disabling
+        /// this speeds up tests where fsync is a bottleneck.
+        #[arg(long, group = "settings")]
+        keeper_force_sync: Option<bool>,
+
+        /// Four letter word commands keepers accept, e.g. `ruok,stat`. The
+        /// words clickward issues itself are always added. ClickHouse's
+        /// default list applies if unset.
+        #[arg(long, value_delimiter = ',', group = "settings")]
+        four_letter_word_allow_list: Vec<String>,
     },
 
     /// Launch our deployment given generated configs
     Deploy {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        spawn: SpawnArgs,
+
+        #[command(flatten)]
+        timing: TimingArgs,
+
+        /// Block until the keepers have a leader and every clickhouse server
+        /// answers `/ping`
+        #[arg(long)]
+        wait: bool,
+
+        /// How many seconds `--wait` waits before giving up
+        #[arg(long, default_value_t = 60, requires = "wait")]
+        wait_timeout: u64,
     },
 
     /// Stop all our deployed processes
     Teardown {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+    },
+
+    /// Stop all our deployed processes and delete their data
+    Wipe {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Also delete the generated configuration files
+        #[arg(long)]
+        include_configs: bool,
+    },
+
+    /// Generate a copy of the deployment at another path, with every port
+    /// shifted so both can run at once. No data is copied.
+    Clone {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        timing: TimingArgs,
+
+        /// Root path of the copy
+        #[arg(long)]
+        to: Utf8PathBuf,
+
+        /// How far to shift every port of the copy
+        #[arg(long, default_value_t = 100)]
+        port_offset: u16,
+
+        /// Name of the copy's clickhouse cluster [default: the original's]
+        #[arg(long)]
+        cluster_name: Option<String>,
+
+        /// Secret the copy's servers use to talk to each other [default: the
+        /// original's]
+        #[arg(long)]
+        secret: Option<String>,
+    },
+
+    /// Rebuild lost metadata from the configs of the deployment's nodes
+    Recover {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Accept previously recovered metadata after checking it, so that
+        /// configs may be regenerated from it again
+        #[arg(long)]
+        accept: bool,
     },
 
     /// Show metadata about the deployment
     Show {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+    },
+
+    /// Show whether each node of the deployment is running
+    Status {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+    },
+
+    /// List every port bound by the nodes of the deployment
+    Ports {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+    },
+
+    /// Print the effective config of a node as JSON
+    Describe {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Id of the clickhouse server to describe
+        #[arg(
+            long,
+            required_unless_present = "keeper",
+            conflicts_with = "keeper"
+        )]
+        server: Option<u64>,
+
+        /// Id of the keeper to describe
+        #[arg(long)]
+        keeper: Option<u64>,
+    },
+
+    /// Run SQL against the clickhouse servers of the deployment
+    ExecSql {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// The SQL to run. May contain several statements separated by `;`.
+        #[arg(long)]
+        query: String,
+
+        /// Run the query on every clickhouse server
+        #[arg(
+            long,
+            required_unless_present = "on_one",
+            conflicts_with = "on_one"
+        )]
+        all: bool,
+
+        /// Run the query on a single running clickhouse server, e.g. for
+        /// `ON CLUSTER` DDL
+        #[arg(long)]
+        on_one: bool,
+    },
+
+    /// Print numeric health metrics about the deployment
+    Metrics {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
     },
 
     /// Add a keeper node to the keeper cluster
     AddKeeper {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        spawn: SpawnArgs,
+
+        /// Reuse the id of a previously removed keeper
+        #[arg(long)]
+        id: Option<u64>,
     },
 
     /// Remove a keeper node
     RemoveKeeper {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
 
         /// Id of the keeper node to remove
+        #[arg(long, required_unless_present = "ids", conflicts_with = "ids")]
+        id: Option<u64>,
+
+        /// Ids of several keeper nodes to remove, e.g. `2-4,7`
+        #[arg(long, value_parser = parse_id_set::<KeeperId>)]
+        ids: Option<BTreeSet<KeeperId>>,
+
+        /// Also delete the directory of each removed keeper, including its
+        /// data
+        #[arg(long)]
+        purge: bool,
+    },
+
+    /// Stop a keeper but keep it in the cluster, so `deploy` skips it
+    DisableKeeper {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Id of the keeper node
         #[arg(long)]
         id: u64,
     },
 
+    /// Start a disabled keeper again
+    EnableKeeper {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        spawn: SpawnArgs,
+
+        /// Id of the keeper node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Set the raft priority of a keeper to influence leader election
+    SetKeeperPriority {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Id of the keeper node
+        #[arg(long)]
+        id: u64,
+
+        /// Raft priority: higher is more likely to become leader, 0 never will
+        #[arg(long)]
+        priority: u8,
+    },
+
     /// Get the keeper config from a given keeper
     KeeperConfig {
         /// Id of the keeper node to remove
@@ -77,67 +516,499 @@ enum Commands {
         id: u64,
     },
 
+    /// Get connection and latency stats from a given keeper
+    KeeperStats {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Id of the keeper node
+        #[arg(long)]
+        id: u64,
+    },
+
     /// Add a clickhouse server
     AddServer {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        spawn: SpawnArgs,
+
+        /// Reuse the id of a previously removed clickhouse server
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Wait until every running server has reloaded the new cluster config
+        #[arg(long)]
+        verify: bool,
     },
 
     /// Remove a clickhouse server
     RemoveServer {
-        /// Root path of all configuration
-        #[arg(short, long)]
-        path: Utf8PathBuf,
+        #[command(flatten)]
+        deployment: DeploymentArgs,
 
         /// Id of the clickhouse server node to remove
+        #[arg(long, required_unless_present = "ids", conflicts_with = "ids")]
+        id: Option<u64>,
+
+        /// Ids of several clickhouse server nodes to remove, e.g. `2-4,7`
+        #[arg(long, value_parser = parse_id_set::<ServerId>)]
+        ids: Option<BTreeSet<ServerId>>,
+
+        /// Also delete the directory of each removed server, including its
+        /// data
+        #[arg(long)]
+        purge: bool,
+
+        /// Wait until every running server has reloaded the new cluster config
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// Stop a clickhouse server but keep it in the cluster, so `deploy` skips
+    /// it
+    DisableServer {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        /// Id of the clickhouse server node
+        #[arg(long)]
+        id: u64,
+    },
+
+    /// Start a disabled clickhouse server again
+    EnableServer {
+        #[command(flatten)]
+        deployment: DeploymentArgs,
+
+        #[command(flatten)]
+        spawn: SpawnArgs,
+
+        /// Id of the clickhouse server node
         #[arg(long)]
         id: u64,
     },
 }
 
+/// How long `--verify` waits for servers to reload their config
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `keeper-config` waits for the keeper to catch up with the leader
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
 //const CLUSTER: &str = "test_cluster";
 const CLUSTER: &str = "oximeter_cluster";
 
 #[tokio::main]
 async fn main() {
+    // Set `RUST_LOG=debug` to e.g. see the command line of every spawned node
+    env_logger::init();
     if let Err(e) = handle().await {
-        println!("Error: {e}");
+        println!("Error: {e:#}");
     }
 }
 
 async fn handle() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let output_format = cli.output;
     match cli.command {
-        Commands::GenConfig { path, num_keepers, num_replicas } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.generate_config(num_keepers, num_replicas)
+        Commands::GenConfig {
+            deployment,
+            timing,
+            from_spec,
+            replace,
+            num_keepers,
+            num_replicas,
+            format,
+            async_insert,
+            async_insert_busy_timeout_ms,
+            async_insert_max_data_size,
+            disks,
+            policies,
+            no_span_log,
+            loopback_per_node,
+            listen_hosts,
+            listen_try,
+            num_shards,
+            shard_weights,
+            replica_macro_scheme,
+            max_memory,
+            background_pool_size,
+            mark_cache_size,
+            max_thread_pool_size,
+            max_io_thread_pool_size,
+            mlock_executable,
+            prometheus,
+            prometheus_endpoint,
+            keep_alive_timeout,
+            keeper_async_replication,
+            keeper_quorum_reads,
+            keeper_force_sync,
+            four_letter_word_allow_list,
+        } => {
+            if replace {
+                let mut d = Deployment::try_new(deployment.config()?)?;
+                timing.apply(&mut d);
+                d.regenerate_all()?;
+                println!("Regenerated configs from existing metadata");
+                return Ok(());
+            }
+            let mut d = deployment.load()?;
+            timing.apply(&mut d);
+            let mut spec = match &from_spec {
+                Some(path) => DeploymentSpec::load(path)?,
+                // clap ensures both are present without a spec
+                None => DeploymentSpec::new(
+                    num_keepers.unwrap_or_default(),
+                    num_replicas.unwrap_or_default(),
+                ),
+            };
+            spec.normalize();
+
+            // Flags override the spec
+            let settings = &mut spec.settings;
+            if let Some(n) = num_keepers {
+                spec.num_keepers = n;
+            }
+            if let Some(n) = num_replicas {
+                spec.num_replicas = n;
+            }
+            if let Some(format) = format {
+                settings.format = format;
+            }
+            if async_insert {
+                let mut c = settings.async_insert.take().unwrap_or_default();
+                if let Some(ms) = async_insert_busy_timeout_ms {
+                    c.busy_timeout_ms = ms;
+                }
+                if let Some(size) = async_insert_max_data_size {
+                    c.max_data_size = size;
+                }
+                settings.async_insert = Some(c);
+            }
+            if !disks.is_empty() {
+                settings.storage.disks = disks;
+            }
+            if !policies.is_empty() {
+                settings.storage.policies = policies;
+            }
+            if no_span_log {
+                settings.omit_span_log = true;
+            }
+            if loopback_per_node {
+                settings.loopback_per_node = true;
+            }
+            if !listen_hosts.is_empty() {
+                settings.listen_hosts = listen_hosts;
+            }
+            if listen_try {
+                settings.listen_try = true;
+            }
+            if let Some(n) = num_shards {
+                settings.shards.num_shards = n;
+            }
+            for ShardWeight { shard, weight } in shard_weights {
+                settings.shards.weights.insert(shard, weight);
+            }
+            if let Some(scheme) = replica_macro_scheme {
+                settings.shards.replica_macros = scheme;
+            }
+            let limits = &mut settings.resource_limits;
+            if max_memory.is_some() {
+                limits.max_server_memory_usage = max_memory;
+            }
+            if background_pool_size.is_some() {
+                limits.background_pool_size = background_pool_size;
+            }
+            if mark_cache_size.is_some() {
+                limits.mark_cache_size = mark_cache_size;
+            }
+            let tuning = &mut settings.runtime_tuning;
+            if max_thread_pool_size.is_some() {
+                tuning.max_thread_pool_size = max_thread_pool_size;
+            }
+            if max_io_thread_pool_size.is_some() {
+                tuning.max_io_thread_pool_size = max_io_thread_pool_size;
+            }
+            if mlock_executable {
+                tuning.mlock_executable = Some(true);
+            }
+            if prometheus {
+                settings.prometheus_endpoint = Some(prometheus_endpoint);
+            }
+            if keep_alive_timeout.is_some() {
+                settings.keep_alive_timeout = keep_alive_timeout;
+            }
+            let raft = &mut settings.keeper_raft;
+            if keeper_async_replication.is_some() {
+                raft.async_replication = keeper_async_replication;
+            }
+            if keeper_quorum_reads.is_some() {
+                raft.quorum_reads = keeper_quorum_reads;
+            }
+            if keeper_force_sync.is_some() {
+                raft.force_sync = keeper_force_sync;
+            }
+            if !four_letter_word_allow_list.is_empty() {
+                settings.four_letter_word_allow_list =
+                    four_letter_word_allow_list;
+            }
+
+            d.generate_config_from_spec(&spec)
         }
-        Commands::Deploy { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.deploy()
+        Commands::Deploy { deployment, spawn, timing, wait, wait_timeout } => {
+            let mut d = deployment.load()?;
+            spawn.apply(&mut d);
+            timing.apply(&mut d);
+            if wait {
+                d.deploy_and_wait(Duration::from_secs(wait_timeout)).await?;
+                println!("Deployment is ready");
+                Ok(())
+            } else {
+                d.deploy()
+            }
         }
-        Commands::Teardown { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.teardown()
+        Commands::Teardown { deployment } => {
+            let d = deployment.load()?;
+            let report = d.teardown()?;
+            print_teardown_report(&report);
+            if !report.succeeded() {
+                bail!("some nodes could not be stopped");
+            }
+            Ok(())
+        }
+        Commands::Wipe { deployment, include_configs } => {
+            let d = deployment.load()?;
+            d.wipe(include_configs)
+        }
+        Commands::Clone {
+            deployment,
+            timing,
+            to,
+            port_offset,
+            cluster_name,
+            secret,
+        } => {
+            let mut d = deployment.load()?;
+            timing.apply(&mut d);
+            let base_ports = d
+                .base_ports()
+                .shifted(port_offset)
+                .ok_or_else(|| anyhow!("--port-offset is too large"))?;
+            let clone = d.clone_to(
+                &deployment_dir(&to),
+                base_ports,
+                cluster_name,
+                secret,
+            )?;
+            println!("Generated a copy of {} in {}", d.path(), clone.path());
+            Ok(())
+        }
+        Commands::Recover { deployment, accept: true } => {
+            let mut d = deployment.load()?;
+            d.accept_recovered_metadata()?;
+            println!("Accepted the metadata of {}", d.path());
+            Ok(())
         }
-        Commands::Show { path } => {
-            let d = Deployment::new_with_default_port_config(path, CLUSTER);
+        Commands::Recover { deployment, accept: false } => {
+            let path = deployment.config()?.path;
+            if path.join(CLICKWARD_META_FILENAME).exists() {
+                bail!("{path} already has metadata: refusing to overwrite it");
+            }
+            let meta = Deployment::recover_metadata(&path)?;
+            meta.save(&path)?;
+            println!("Recovered metadata: {meta:#?}");
+            println!(
+                "Settings other than the format, cluster name, secret, base \
+                 ports, shard topology and four letter word allow list have \
+                 their default values. Check {} and run `recover --accept` \
+                 before changing the deployment.",
+                path.join(CLICKWARD_META_FILENAME)
+            );
+            Ok(())
+        }
+        Commands::Show { deployment } => {
+            let names = match deployment.name {
+                Some(_) => vec![],
+                None => named_deployments(&deployment.path)?,
+            };
+            let d = match Deployment::try_new(deployment.config()?) {
+                Ok(d) => d,
+                Err(e @ ClickwardError::CorruptMetadata { .. }) => {
+                    bail!("{e}: fix or delete it and rerun `gen-config`")
+                }
+                Err(e) => return Err(e.into()),
+            };
             match &d.meta() {
-                Some(meta) => println!("{:#?}", meta),
+                Some(meta) if output_format == OutputFormat::Json => {
+                    let output = ShowOutput {
+                        path: d.path().to_path_buf(),
+                        cluster: d.cluster_name().to_string(),
+                        metadata: meta.clone(),
+                        running: d.running_counts()?,
+                    };
+                    print_output(output_format, &output)?;
+                }
+                None if output_format == OutputFormat::Json => {
+                    let output =
+                        NamedDeploymentsOutput { named_deployments: names };
+                    print_output(output_format, &output)?;
+                }
+                Some(meta) => {
+                    println!("Path: {}", d.path());
+                    println!("Cluster: {}", d.cluster_name());
+                    println!("{:#?}", meta);
+                    let counts = d.running_counts()?;
+                    println!(
+                        "Running keepers: {}/{}",
+                        counts.keepers_running, counts.keepers_total
+                    );
+                    println!(
+                        "Running servers: {}/{}",
+                        counts.servers_running, counts.servers_total
+                    );
+                }
+                None if !names.is_empty() => {
+                    println!("Named deployments (select one with `--name`):");
+                    for name in names {
+                        println!("    {name}");
+                    }
+                }
                 None => println!(
                     "No deployment generated: Please call `gen-config`"
                 ),
             }
             Ok(())
         }
-        Commands::AddKeeper { path } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.add_keeper()
+        Commands::Status { deployment } => {
+            let d = deployment.load()?;
+            print_output(output_format, &d.status()?)
+        }
+        Commands::Ports { deployment } => {
+            let d = deployment.load()?;
+            let ports = d.port_map()?;
+            if output_format == OutputFormat::Json {
+                let ports: Vec<_> = ports
+                    .into_iter()
+                    .map(|(port, kind, node)| PortOutput { port, kind, node })
+                    .collect();
+                return print_output(output_format, &ports);
+            }
+            for (port, kind, node) in ports {
+                println!("{port}\t{kind}\t{node}");
+            }
+            Ok(())
+        }
+        Commands::Describe { deployment, server, keeper } => {
+            let d = deployment.load()?;
+            let json = match (server, keeper) {
+                (Some(id), _) => {
+                    serde_json::to_string_pretty(&d.server_config(id.into())?)?
+                }
+                (None, Some(id)) => {
+                    serde_json::to_string_pretty(&d.keeper_config(id.into())?)?
+                }
+                (None, None) => {
+                    unreachable!("clap requires --server or --keeper")
+                }
+            };
+            println!("{json}");
+            Ok(())
+        }
+        Commands::ExecSql { deployment, query, all: _, on_one } => {
+            let d = deployment.load()?;
+            if on_one {
+                let (id, output) = d.run_sql_on_one(&query).await?;
+                println!("clickhouse server {id}:");
+                print!("{output}");
+                return Ok(());
+            }
+            let results = d.run_sql_on_all(&query).await?;
+            let mut failed = vec![];
+            for (id, res) in results {
+                println!("clickhouse server {id}:");
+                match res {
+                    Ok(output) => print!("{output}"),
+                    Err(e) => {
+                        println!("FAILED: {e}");
+                        failed.push(id.to_string());
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                bail!(
+                    "query failed on clickhouse servers: {}",
+                    failed.join(", ")
+                );
+            }
+            Ok(())
+        }
+        Commands::Metrics { deployment } => {
+            let d = deployment.load()?;
+            let metrics = d.metrics().await?;
+            if output_format == OutputFormat::Json {
+                print_output(output_format, &metrics)?;
+            } else {
+                let or_none =
+                    |v: Option<String>| v.unwrap_or_else(|| "none".to_string());
+                println!("keepers_total: {}", metrics.keepers_total);
+                println!("keepers_running: {}", metrics.keepers_running);
+                println!("servers_total: {}", metrics.servers_total);
+                println!("servers_running: {}", metrics.servers_running);
+                println!(
+                    "leader_keeper_id: {}",
+                    or_none(metrics.leader_keeper_id.map(|id| id.to_string()))
+                );
+                println!(
+                    "synced_followers: {}",
+                    or_none(metrics.synced_followers.map(|n| n.to_string()))
+                );
+            }
+            Ok(())
+        }
+        Commands::AddKeeper { deployment, spawn, id } => {
+            let mut d = deployment.load()?;
+            spawn.apply(&mut d);
+            let change = match id {
+                Some(id) => d.add_keeper_with_id(id.into()).await?,
+                None => d.add_keeper().await?,
+            };
+            print_membership_change(change);
+            Ok(())
+        }
+        Commands::RemoveKeeper { deployment, id, ids, purge } => {
+            let mut d = deployment.load()?;
+            let ids = match (id, ids) {
+                (_, Some(ids)) => {
+                    d.remove_keepers(&ids).await?;
+                    ids
+                }
+                (id, None) => {
+                    let id = id.expect("clap requires --id or --ids").into();
+                    ignore_already_removed(d.remove_keeper(id).await)?;
+                    BTreeSet::from([id])
+                }
+            };
+            if purge {
+                for id in ids {
+                    d.purge_node(NodeRef::Keeper(id))?;
+                }
+            }
+            Ok(())
         }
-        Commands::RemoveKeeper { path, id } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.remove_keeper(id.into())
+        Commands::DisableKeeper { deployment, id } => {
+            let mut d = deployment.load()?;
+            d.disable_keeper(id.into())
+        }
+        Commands::EnableKeeper { deployment, spawn, id } => {
+            let mut d = deployment.load()?;
+            spawn.apply(&mut d);
+            d.enable_keeper(id.into())
+        }
+        Commands::SetKeeperPriority { deployment, id, priority } => {
+            let mut d = deployment.load()?;
+            d.set_keeper_priority(id.into(), priority)
         }
         Commands::KeeperConfig { id } => {
             // Unused
@@ -146,17 +1017,105 @@ async fn handle() -> anyhow::Result<()> {
                 Deployment::new_with_default_port_config(dummy_path, CLUSTER);
             let addr = d.keeper_addr(id.into())?;
             let zk = KeeperClient::new(addr);
-            let output = zk.config().await?;
-            println!("{output:#?}");
+            // A lagging follower may report stale membership
+            zk.wait_synced(SYNC_TIMEOUT).await?;
+            print_output(output_format, &zk.config().await?)
+        }
+        Commands::KeeperStats { deployment, id } => {
+            let d = deployment.load()?;
+            let Some(meta) = d.meta() else {
+                bail!("No deployment generated: Please call `gen-config`");
+            };
+            meta.check_member(NodeRef::Keeper(id.into()))?;
+            let zk = KeeperClient::new(d.keeper_addr(id.into())?);
+            print_output(output_format, &zk.srvr().await?)
+        }
+        Commands::AddServer { deployment, spawn, id, verify } => {
+            let mut d = deployment.load()?;
+            spawn.apply(&mut d);
+            let change = match id {
+                Some(id) => d.add_server_with_id(id.into())?,
+                None => d.add_server()?,
+            };
+            print_membership_change(change);
+            if verify && change == MembershipChange::Live {
+                d.wait_for_cluster_reload(RELOAD_TIMEOUT).await?;
+                println!("All running servers see the new cluster config");
+            }
             Ok(())
         }
-        Commands::AddServer { path } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.add_server()
+        Commands::DisableServer { deployment, id } => {
+            let mut d = deployment.load()?;
+            d.disable_server(id.into())
         }
-        Commands::RemoveServer { path, id } => {
-            let mut d = Deployment::new_with_default_port_config(path, CLUSTER);
-            d.remove_server(id.into())
+        Commands::EnableServer { deployment, spawn, id } => {
+            let mut d = deployment.load()?;
+            spawn.apply(&mut d);
+            d.enable_server(id.into())
         }
+        Commands::RemoveServer { deployment, id, ids, verify, purge } => {
+            let mut d = deployment.load()?;
+            let ids = match (id, ids) {
+                (_, Some(ids)) => {
+                    d.remove_servers(&ids)?;
+                    ids
+                }
+                (id, None) => {
+                    let id = id.expect("clap requires --id or --ids").into();
+                    ignore_already_removed(d.remove_server(id))?;
+                    BTreeSet::from([id])
+                }
+            };
+            if purge {
+                for id in ids {
+                    d.purge_node(NodeRef::Server(id))?;
+                }
+            }
+            if verify && d.is_deployed() {
+                d.wait_for_cluster_reload(RELOAD_TIMEOUT).await?;
+                println!("All running servers see the new cluster config");
+            }
+            Ok(())
+        }
+    }
+}
+
+fn print_membership_change(change: MembershipChange) {
+    match change {
+        MembershipChange::Live => {
+            println!("Membership change applied to the running cluster")
+        }
+        MembershipChange::ConfigOnly => println!(
+            "Deployment is not running: only configs were regenerated. \
+             Run `deploy` to start the cluster."
+        ),
+    }
+}
+
+fn print_teardown_report(report: &TeardownReport) {
+    let describe = |outcome: &StopOutcome| match outcome {
+        StopOutcome::Stopped => "stopped".to_string(),
+        StopOutcome::WasNotRunning => "was not running".to_string(),
+        StopOutcome::Failed(err) => format!("FAILED: {err}"),
+    };
+    for (id, outcome) in &report.keepers {
+        println!("keeper {id}: {}", describe(outcome));
+    }
+    for (id, outcome) in &report.servers {
+        println!("clickhouse server {id}: {}", describe(outcome));
+    }
+}
+
+/// Removing a node that was already removed is not an error for the CLI
+fn ignore_already_removed(res: anyhow::Result<()>) -> anyhow::Result<()> {
+    match res {
+        Err(e) => match e.downcast_ref::<ClickwardError>() {
+            Some(ClickwardError::AlreadyRemoved(node)) => {
+                println!("Nothing to do: {node} was already removed");
+                Ok(())
+            }
+            _ => Err(e),
+        },
+        Ok(()) => Ok(()),
     }
 }
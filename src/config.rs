@@ -3,14 +3,17 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{KeeperId, ServerId};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use schemars::{
     gen::SchemaGenerator,
     schema::{Schema, SchemaObject},
     JsonSchema,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 
 // Used for schemars to be able to be used with camino:
 // See https://github.com/camino-rs/camino/issues/91#issuecomment-2027908513
@@ -20,12 +23,479 @@ fn path_schema(gen: &mut SchemaGenerator) -> Schema {
     schema.into()
 }
 
+/// Quote a string so that it is always a valid YAML scalar
+fn yaml_str(s: impl AsRef<str>) -> String {
+    // A JSON string literal is also a valid double-quoted YAML scalar
+    serde_json::to_string(s.as_ref()).unwrap()
+}
+
+/// Render a YAML scalar, leaving integers and booleans unquoted
+fn yaml_scalar(s: &str) -> String {
+    let is_int = !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if is_int || s == "true" || s == "false" {
+        s.to_string()
+    } else {
+        yaml_str(s)
+    }
+}
+
+/// Escape the characters that can't appear literally in XML text
+fn xml_escape(s: impl AsRef<str>) -> String {
+    s.as_ref().replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Check that `name` can be used as both an XML element name and a plain
+/// YAML key, as disk, policy, volume and cluster names are
+pub fn validate_element_name(kind: &str, name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid =
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+    if !valid {
+        return Err(format!(
+            "invalid {kind} name {name:?}: names must start with a letter or \
+             underscore and contain only letters, digits, '_', '-' and '.'"
+        ));
+    }
+    Ok(())
+}
+
+/// A section of a generated config file
+///
+/// Configs are built as a tree of these and then rendered as either XML or
+/// YAML, so that both formats always have the same contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigNode {
+    /// The text of an element in XML, and a scalar in YAML
+    Value(String),
+    /// Named children, in order. In XML, keys starting with `@` are rendered
+    /// as attributes of the parent element.
+    Map(Vec<(String, ConfigNode)>),
+    /// Repeated elements of the same name in XML, and a sequence in YAML
+    Seq(Vec<ConfigNode>),
+}
+
+impl ConfigNode {
+    pub fn value(value: impl Display) -> ConfigNode {
+        ConfigNode::Value(value.to_string())
+    }
+
+    /// An empty map, to be filled with `with` and friends
+    pub fn map() -> ConfigNode {
+        ConfigNode::Map(vec![])
+    }
+
+    /// Add `key` to a map. Other nodes are left as they are.
+    pub fn with(self, key: impl Into<String>, node: ConfigNode) -> ConfigNode {
+        self.with_opt(key, Some(node))
+    }
+
+    pub fn with_value(
+        self,
+        key: impl Into<String>,
+        value: impl Display,
+    ) -> ConfigNode {
+        self.with(key, ConfigNode::value(value))
+    }
+
+    /// Add `key` to a map if `node` is set
+    pub fn with_opt(
+        mut self,
+        key: impl Into<String>,
+        node: Option<ConfigNode>,
+    ) -> ConfigNode {
+        if let (ConfigNode::Map(entries), Some(node)) = (&mut self, node) {
+            entries.push((key.into(), node));
+        }
+        self
+    }
+
+    /// Add all entries of the map `other` to a map
+    pub fn merge(mut self, other: ConfigNode) -> ConfigNode {
+        if let (ConfigNode::Map(entries), ConfigNode::Map(other)) =
+            (&mut self, other)
+        {
+            entries.extend(other);
+        }
+        self
+    }
+
+    /// Parse a config file, e.g. one written by `render`, back into a tree
+    ///
+    /// Repeated XML elements become a `Seq`, and XML attributes `@` keys. An
+    /// element that is only given once parses as a single node, so use
+    /// `items` to read anything that may be repeated.
+    pub fn parse(
+        contents: &str,
+        format: ConfigFormat,
+    ) -> Result<ConfigNode, String> {
+        match format {
+            ConfigFormat::Xml => {
+                let doc = roxmltree::Document::parse(contents)
+                    .map_err(|e| e.to_string())?;
+                Ok(ConfigNode::from_xml(doc.root_element()))
+            }
+            ConfigFormat::Yaml => {
+                let docs = yaml_rust2::YamlLoader::load_from_str(contents)
+                    .map_err(|e| e.to_string())?;
+                match docs.first() {
+                    Some(doc) => ConfigNode::from_yaml(doc),
+                    None => Ok(ConfigNode::map()),
+                }
+            }
+        }
+    }
+
+    fn from_xml(element: roxmltree::Node) -> ConfigNode {
+        let children: Vec<_> =
+            element.children().filter(|n| n.is_element()).collect();
+        if children.is_empty() && element.attributes().len() == 0 {
+            let text = element.text().unwrap_or_default().trim();
+            return ConfigNode::value(text);
+        }
+        let mut entries: Vec<(String, ConfigNode)> = element
+            .attributes()
+            .map(|a| (format!("@{}", a.name()), ConfigNode::value(a.value())))
+            .collect();
+        for child in children {
+            let name = child.tag_name().name();
+            let node = ConfigNode::from_xml(child);
+            match entries.iter_mut().find(|(key, _)| key == name) {
+                Some((_, ConfigNode::Seq(items))) => items.push(node),
+                Some((_, existing)) => {
+                    let first = std::mem::replace(existing, ConfigNode::map());
+                    *existing = ConfigNode::Seq(vec![first, node]);
+                }
+                None => entries.push((name.to_string(), node)),
+            }
+        }
+        ConfigNode::Map(entries)
+    }
+
+    fn from_yaml(yaml: &yaml_rust2::Yaml) -> Result<ConfigNode, String> {
+        use yaml_rust2::Yaml;
+        Ok(match yaml {
+            Yaml::Real(v) | Yaml::String(v) => ConfigNode::value(v),
+            Yaml::Integer(v) => ConfigNode::value(v),
+            Yaml::Boolean(v) => ConfigNode::value(v),
+            Yaml::Null => ConfigNode::value(""),
+            Yaml::Array(items) => ConfigNode::Seq(
+                items
+                    .iter()
+                    .map(ConfigNode::from_yaml)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Yaml::Hash(hash) => {
+                let mut entries = vec![];
+                for (key, value) in hash {
+                    let Some(key) = key.as_str() else {
+                        return Err(format!("unexpected YAML key: {key:?}"));
+                    };
+                    entries
+                        .push((key.to_string(), ConfigNode::from_yaml(value)?));
+                }
+                ConfigNode::Map(entries)
+            }
+            Yaml::Alias(_) | Yaml::BadValue => {
+                return Err(format!("unsupported YAML value: {yaml:?}"));
+            }
+        })
+    }
+
+    /// Return the child `key` of a map
+    pub fn get(&self, key: &str) -> Option<&ConfigNode> {
+        match self {
+            ConfigNode::Map(entries) => {
+                entries.iter().find(|(k, _)| k == key).map(|(_, node)| node)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the text of a value
+    pub fn as_value(&self) -> Option<&str> {
+        match self {
+            ConfigNode::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Return the items of a sequence, or the node itself if it isn't one
+    pub fn items(&self) -> &[ConfigNode] {
+        match self {
+            ConfigNode::Seq(items) => items,
+            node => std::slice::from_ref(node),
+        }
+    }
+
+    /// Render a whole config file, with this node as the `<clickhouse>` root
+    pub fn render(&self, format: ConfigFormat) -> String {
+        match format {
+            ConfigFormat::Xml => self.to_xml("clickhouse", 0),
+            ConfigFormat::Yaml => match self {
+                // The root element is implicit in YAML
+                ConfigNode::Map(entries) => {
+                    let mut s = String::new();
+                    write_yaml_entries(entries, 0, &mut s);
+                    s
+                }
+                node => node.to_yaml(),
+            },
+        }
+    }
+
+    /// Render this node as XML element(s) named `name`, indented for
+    /// `depth` levels of nesting
+    pub fn to_xml(&self, name: &str, depth: usize) -> String {
+        let mut s = String::new();
+        self.write_xml(name, depth, &mut s);
+        s
+    }
+
+    fn write_xml(&self, name: &str, depth: usize, out: &mut String) {
+        let indent = "    ".repeat(depth);
+        match self {
+            ConfigNode::Value(value) => {
+                let value = xml_escape(value);
+                out.push_str(&format!("{indent}<{name}>{value}</{name}>\n"));
+            }
+            ConfigNode::Seq(items) => {
+                for item in items {
+                    item.write_xml(name, depth, out);
+                }
+            }
+            ConfigNode::Map(entries) => {
+                let mut attrs = String::new();
+                let mut children = vec![];
+                for (key, node) in entries {
+                    match (key.strip_prefix('@'), node) {
+                        (Some(attr), ConfigNode::Value(value)) => {
+                            let value =
+                                xml_escape(value).replace('"', "&quot;");
+                            attrs.push_str(&format!(" {attr}=\"{value}\""));
+                        }
+                        _ => children.push((key, node)),
+                    }
+                }
+                if children.is_empty() {
+                    out.push_str(&format!("{indent}<{name}{attrs}/>\n"));
+                    return;
+                }
+                out.push_str(&format!("{indent}<{name}{attrs}>\n"));
+                for (key, node) in children {
+                    node.write_xml(key, depth + 1, out);
+                }
+                out.push_str(&format!("{indent}</{name}>\n"));
+            }
+        }
+    }
+
+    /// Render this node as a YAML document
+    pub fn to_yaml(&self) -> String {
+        let mut s = String::new();
+        match self {
+            ConfigNode::Value(value) => s.push_str(&yaml_scalar(value)),
+            ConfigNode::Map(entries) => write_yaml_entries(entries, 0, &mut s),
+            ConfigNode::Seq(items) => {
+                for item in items {
+                    write_yaml_item(item, 0, &mut s);
+                }
+            }
+        }
+        s
+    }
+}
+
+/// Write the entries of a YAML mapping, indented by `indent` spaces
+fn write_yaml_entries(
+    entries: &[(String, ConfigNode)],
+    indent: usize,
+    out: &mut String,
+) {
+    let pad = " ".repeat(indent);
+    for (key, node) in entries {
+        // `@` can't start a plain scalar
+        let key =
+            if key.starts_with('@') { yaml_str(key) } else { key.clone() };
+        match node {
+            ConfigNode::Value(value) => {
+                out.push_str(&format!("{pad}{key}: {}\n", yaml_scalar(value)));
+            }
+            ConfigNode::Map(entries) if entries.is_empty() => {
+                out.push_str(&format!("{pad}{key}: {{}}\n"));
+            }
+            ConfigNode::Map(entries) => {
+                out.push_str(&format!("{pad}{key}:\n"));
+                write_yaml_entries(entries, indent + 4, out);
+            }
+            ConfigNode::Seq(items) if items.is_empty() => {
+                out.push_str(&format!("{pad}{key}: []\n"));
+            }
+            ConfigNode::Seq(items) => {
+                out.push_str(&format!("{pad}{key}:\n"));
+                for item in items {
+                    write_yaml_item(item, indent + 4, out);
+                }
+            }
+        }
+    }
+}
+
+/// Write an item of a YAML sequence, indented by `indent` spaces
+fn write_yaml_item(item: &ConfigNode, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match item {
+        ConfigNode::Value(value) => {
+            out.push_str(&format!("{pad}- {}\n", yaml_scalar(value)));
+        }
+        ConfigNode::Map(entries) if entries.is_empty() => {
+            out.push_str(&format!("{pad}- {{}}\n"));
+        }
+        ConfigNode::Map(entries) => {
+            // The first entry goes on the same line as the dash
+            let mut s = String::new();
+            write_yaml_entries(entries, indent + 2, &mut s);
+            out.push_str(&format!("{pad}- {}", &s[indent + 2..]));
+        }
+        ConfigNode::Seq(items) => {
+            out.push_str(&format!("{pad}-\n"));
+            for item in items {
+                write_yaml_item(item, indent + 2, out);
+            }
+        }
+    }
+}
+
+/// A map with a `listen_host` per host, followed by `listen_try` if set
+fn listen_node(hosts: &[String], listen_try: bool) -> ConfigNode {
+    let hosts = hosts.iter().map(ConfigNode::value).collect();
+    ConfigNode::map()
+        .with("listen_host", ConfigNode::Seq(hosts))
+        .with_opt("listen_try", listen_try.then(|| ConfigNode::value(1)))
+}
+
+/// The config section a host is rendered into
+///
+/// ClickHouse parses hosts differently depending on where they appear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostContext {
+    /// A `<zookeeper><node><host>` element. ClickHouse joins the host and
+    /// port into a single `host:port` string, so IPv6 addresses must be
+    /// bracketed.
+    Zookeeper,
+
+    /// A `<remote_servers>` replica `<host>` element. The host is resolved
+    /// on its own, so IPv6 addresses must *not* be bracketed.
+    RemoteServers,
+}
+
+/// Format `host` for a `<host>` element in the given context
+///
+/// `host` may be an IPv4 address, an IPv6 address with or without brackets, or
+/// a DNS name. Only IPv6 addresses are ever bracketed.
+pub fn format_host(host: &str, context: HostContext) -> String {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    match (unbracketed.parse::<Ipv6Addr>(), context) {
+        (Ok(addr), HostContext::Zookeeper) => format!("[{addr}]"),
+        (Ok(addr), HostContext::RemoteServers) => addr.to_string(),
+        (Err(_), _) => unbracketed.to_string(),
+    }
+}
+
+/// The file format of generated clickhouse and keeper configuration
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    #[default]
+    Xml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The file extension used for config files of this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Xml => "xml",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+}
+
+impl Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xml" => Ok(ConfigFormat::Xml),
+            "yaml" => Ok(ConfigFormat::Yaml),
+            _ => Err(format!("unknown config format: {s}")),
+        }
+    }
+}
+
+/// The `opentelemetry_span_log` table definition
+///
+/// In newer versions of ClickHouse this table is created automatically, and
+/// this can be omitted via `ReplicaConfig::omit_span_log`.
+fn opentelemetry_span_log() -> ConfigNode {
+    ConfigNode::map()
+        .with_value(
+            "engine",
+            "engine MergeTree partition by toYYYYMM(finish_date) \
+             order by (finish_date, finish_time_us, trace_id)",
+        )
+        .with_value("database", "system")
+        .with_value("table", "opentelemetry_span_log")
+        .with_value("flush_interval_milliseconds", 7500)
+}
+
+/// The definition of a system log table that samples metrics
+fn metric_log(table: &str) -> ConfigNode {
+    ConfigNode::map()
+        .with_value("database", "system")
+        .with_value("table", table)
+        .with_value("flush_interval_milliseconds", 7500)
+        .with_value("collect_interval_milliseconds", 1000)
+        .with_value("max_size_rows", 1048576)
+        .with_value("reserved_size_rows", 8192)
+        .with_value("buffer_size_rows_flush_threshold", 524288)
+        .with_value("flush_on_crash", false)
+}
+
 /// Config for an individual Clickhouse Replica
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
 pub struct ReplicaConfig {
+    /// Used for the display name, which must be unique even where the
+    /// `{replica}` macro isn't, e.g. with per shard replica numbering
+    pub server_id: ServerId,
     pub logger: LogConfig,
     pub macros: Macros,
-    pub listen_host: String,
+    /// Each address is rendered as its own `<listen_host>` element
+    pub listen_hosts: Vec<String>,
+    /// Keep starting if some of `listen_hosts` can't be bound, e.g. because
+    /// the host lacks that address family
+    pub listen_try: bool,
+    /// The address other replicas use to fetch parts from this one
+    pub interserver_http_host: String,
     pub http_port: u16,
     pub tcp_port: u16,
     pub interserver_http_port: u16,
@@ -33,199 +503,689 @@ pub struct ReplicaConfig {
     pub keepers: KeeperConfigsForReplica,
     #[schemars(schema_with = "path_schema")]
     pub data_path: Utf8PathBuf,
+    pub async_insert: Option<AsyncInsertConfig>,
+    pub prometheus: Option<PrometheusConfig>,
+    /// Seconds an idle http connection is kept open
+    pub keep_alive_timeout: Option<u32>,
+    /// Rendered as an `<http_handlers>` block if non-empty. The default
+    /// handlers, e.g. `/ping`, are kept alongside these.
+    pub http_handlers: Vec<HttpHandlerRule>,
+    pub storage: StorageConfig,
+    pub resource_limits: ResourceLimits,
+    pub runtime_tuning: RuntimeTuning,
+    /// Don't define the `opentelemetry_span_log` table in the config, and
+    /// rely on ClickHouse creating it automatically instead
+    pub omit_span_log: bool,
 }
 
 impl ReplicaConfig {
-    pub fn to_xml(&self) -> String {
+    pub fn to_node(&self) -> ConfigNode {
         let ReplicaConfig {
+            server_id,
             logger,
             macros,
-            listen_host,
+            listen_hosts,
+            listen_try,
+            interserver_http_host,
             http_port,
             tcp_port,
             interserver_http_port,
             remote_servers,
             keepers,
             data_path,
+            async_insert,
+            prometheus,
+            keep_alive_timeout,
+            http_handlers,
+            storage,
+            resource_limits,
+            runtime_tuning,
+            omit_span_log,
         } = self;
-        let logger = logger.to_xml();
-        let cluster = macros.cluster.clone();
-        let id = macros.replica;
-        let macros = macros.to_xml();
-        let keepers = keepers.to_xml();
-        let remote_servers = remote_servers.to_xml();
-        let user_files_path = data_path.clone().join("user_files");
-        //let access_path = data_path.clone().join("access");
-        let format_schema_path = data_path.clone().join("format_schemas");
-        format!(
-            "
-<clickhouse>
-{logger}
-    <path>{data_path}</path>
-
-    <profiles>
-        <default>
-            <opentelemetry_start_trace_probability>1</opentelemetry_start_trace_probability>
-            <load_balancing>random</load_balancing>
-        </default>
-
-    </profiles>
-
-    <users>
-        <default>
-            <password></password>
-            <networks>
-                <ip>::/0</ip>
-            </networks>
-            <profile>default</profile>
-            <quota>default</quota>
-        </default>
-    </users>
-
-    <quotas>
-        <default>
-            <interval>
-                <duration>3600</duration>
-                <queries>0</queries>
-                <errors>0</errors>
-                <result_rows>0</result_rows>
-                <read_rows>0</read_rows>
-                <execution_time>0</execution_time>
-            </interval>
-        </default>
-    </quotas>
-
-    <user_files_path>{user_files_path}</user_files_path>
-    <default_profile>default</default_profile>
-    <format_schema_path>{format_schema_path}</format_schema_path>
-    <display_name>{cluster}-{id}</display_name>
-    <listen_host>{listen_host}</listen_host>
-    <http_port>{http_port}</http_port>
-    <tcp_port>{tcp_port}</tcp_port>
-    <interserver_http_port>{interserver_http_port}</interserver_http_port>
-    <interserver_http_host>::1</interserver_http_host>
-    <distributed_ddl>
-        <!-- Cleanup settings (active tasks will not be removed) -->
-
-        <!-- Controls task TTL (default 1 week) -->
-        <task_max_lifetime>604800</task_max_lifetime>
-
-        <!-- Controls how often cleanup should be performed (in seconds) -->
-        <cleanup_delay_period>60</cleanup_delay_period>
-
-        <!-- Controls how many tasks could be in the queue -->
-        <max_tasks_in_queue>1000</max_tasks_in_queue>
-     </distributed_ddl>
-{macros}
-{remote_servers}
-{keepers}
-
-    <!-- 
-        In newer versions of ClickHouse this table is created automatically.
-        We should remove this block once we update to a newer version of 
-        ClickHouse that does not need the system.opentelemetry_span_log
-        table to be created via the config.xml file
-    -->
-    <opentelemetry_span_log>
-        <engine>
-            engine MergeTree
-            partition by toYYYYMM(finish_date)
-            order by (finish_date, finish_time_us, trace_id)
-        </engine>
-        <database>system</database>
-        <table>opentelemetry_span_log</table>
-        <flush_interval_milliseconds>7500</flush_interval_milliseconds>
-    </opentelemetry_span_log>
-
-    <metric_log>
-        <database>system</database>
-        <table>metric_log</table>
-        <flush_interval_milliseconds>7500</flush_interval_milliseconds>
-        <collect_interval_milliseconds>1000</collect_interval_milliseconds>
-        <max_size_rows>1048576</max_size_rows>
-        <reserved_size_rows>8192</reserved_size_rows>
-        <buffer_size_rows_flush_threshold>524288</buffer_size_rows_flush_threshold>
-        <flush_on_crash>false</flush_on_crash>
-    </metric_log>
-
-    <asynchronous_metric_log>
-        <database>system</database>
-        <table>asynchronous_metric_log</table>
-        <flush_interval_milliseconds>7500</flush_interval_milliseconds>
-        <collect_interval_milliseconds>1000</collect_interval_milliseconds>
-        <max_size_rows>1048576</max_size_rows>
-        <reserved_size_rows>8192</reserved_size_rows>
-        <buffer_size_rows_flush_threshold>524288</buffer_size_rows_flush_threshold>
-        <flush_on_crash>false</flush_on_crash>
-    </asynchronous_metric_log>
+        let default_profile = ConfigNode::map()
+            .with_value("opentelemetry_start_trace_probability", 1)
+            .with_value("load_balancing", "random");
+        let default_profile = match async_insert {
+            Some(c) => default_profile.merge(c.to_node()),
+            None => default_profile,
+        };
+        let default_user = ConfigNode::map()
+            .with_value("password", "")
+            .with("networks", ConfigNode::map().with_value("ip", "::/0"))
+            .with_value("profile", "default")
+            .with_value("quota", "default");
+        let default_quota = ConfigNode::map().with(
+            "interval",
+            ConfigNode::map()
+                .with_value("duration", 3600)
+                .with_value("queries", 0)
+                .with_value("errors", 0)
+                .with_value("result_rows", 0)
+                .with_value("read_rows", 0)
+                .with_value("execution_time", 0),
+        );
+        // The default handlers, e.g. `/ping`, are kept alongside ours
+        let http_handlers = (!http_handlers.is_empty()).then(|| {
+            let rules = http_handlers.iter().map(|r| r.to_node()).collect();
+            ConfigNode::map()
+                .with("rule", ConfigNode::Seq(rules))
+                .with_value("defaults", "")
+        });
+        // Active tasks are never cleaned up
+        let distributed_ddl = ConfigNode::map()
+            .with_value("task_max_lifetime", 604800)
+            .with_value("cleanup_delay_period", 60)
+            .with_value("max_tasks_in_queue", 1000);
+        ConfigNode::map()
+            .with("logger", logger.to_node())
+            .with_value("path", data_path)
+            .with_opt("storage_configuration", storage.to_node())
+            .merge(resource_limits.to_node())
+            .merge(runtime_tuning.to_node())
+            .with(
+                "profiles",
+                ConfigNode::map().with("default", default_profile),
+            )
+            .with("users", ConfigNode::map().with("default", default_user))
+            .with("quotas", ConfigNode::map().with("default", default_quota))
+            .with_value("user_files_path", data_path.join("user_files"))
+            .with_value("default_profile", "default")
+            .with_value("format_schema_path", data_path.join("format_schemas"))
+            .with_value(
+                "display_name",
+                format!("{}-{server_id}", macros.cluster),
+            )
+            .merge(listen_node(listen_hosts, *listen_try))
+            .with_value("http_port", http_port)
+            .with_value("tcp_port", tcp_port)
+            .with_value("interserver_http_port", interserver_http_port)
+            .with_value("interserver_http_host", interserver_http_host)
+            .with_opt("prometheus", prometheus.as_ref().map(|c| c.to_node()))
+            .with_opt(
+                "keep_alive_timeout",
+                keep_alive_timeout.map(ConfigNode::value),
+            )
+            .with_opt("http_handlers", http_handlers)
+            .with("distributed_ddl", distributed_ddl)
+            .with("macros", macros.to_node())
+            .with("remote_servers", remote_servers.to_node())
+            .with("zookeeper", keepers.to_node())
+            .with_opt(
+                "opentelemetry_span_log",
+                (!omit_span_log).then(opentelemetry_span_log),
+            )
+            .with("metric_log", metric_log("metric_log"))
+            .with(
+                "asynchronous_metric_log",
+                metric_log("asynchronous_metric_log"),
+            )
+    }
 
-</clickhouse>
-"
-        )
+    pub fn to_xml(&self) -> String {
+        self.to_config(ConfigFormat::Xml)
+    }
+
+    pub fn to_yaml(&self) -> String {
+        self.to_config(ConfigFormat::Yaml)
+    }
+
+    /// Render this config in the given format
+    pub fn to_config(&self, format: ConfigFormat) -> String {
+        self.to_node().render(format)
+    }
+}
+
+/// Asynchronous insert settings for the default profile
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct AsyncInsertConfig {
+    /// Maximum time to wait before flushing buffered inserts
+    pub busy_timeout_ms: u64,
+    /// Maximum number of bytes buffered before flushing
+    pub max_data_size: u64,
+}
+
+impl Default for AsyncInsertConfig {
+    /// The ClickHouse defaults
+    fn default() -> Self {
+        AsyncInsertConfig { busy_timeout_ms: 200, max_data_size: 10485760 }
+    }
+}
+
+impl AsyncInsertConfig {
+    /// The settings to add to a profile
+    pub fn to_node(&self) -> ConfigNode {
+        let AsyncInsertConfig { busy_timeout_ms, max_data_size } = self;
+        ConfigNode::map()
+            .with_value("async_insert", 1)
+            .with_value("async_insert_busy_timeout_ms", busy_timeout_ms)
+            .with_value("async_insert_max_data_size", max_data_size)
+    }
+}
+
+/// An http endpoint serving metrics in the Prometheus format
+///
+/// All of `system.metrics`, `system.events` and
+/// `system.asynchronous_metrics` are exported.
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct PrometheusConfig {
+    pub port: u16,
+    pub endpoint: String,
+}
+
+impl PrometheusConfig {
+    pub fn to_node(&self) -> ConfigNode {
+        let PrometheusConfig { port, endpoint } = self;
+        ConfigNode::map()
+            .with_value("endpoint", endpoint)
+            .with_value("port", port)
+            .with_value("metrics", true)
+            .with_value("events", true)
+            .with_value("asynchronous_metrics", true)
+    }
+}
+
+/// A rule routing matching http requests to a handler
+///
+/// See the ClickHouse documentation of `http_handlers` for the available
+/// handler types.
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct HttpHandlerRule {
+    /// The url to match. Prefix it with `regex:` to match a regular
+    /// expression.
+    pub url: String,
+    /// The http methods to match, e.g. `GET`. All methods match if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub handler: HttpHandler,
+}
+
+/// How a matched http request is handled
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct HttpHandler {
+    /// e.g. `static`, `predefined_query_handler` or `replicas_status`
+    #[serde(rename = "type")]
+    pub handler_type: String,
+    /// The query run by a `predefined_query_handler`
+    #[serde(default)]
+    pub query: Option<String>,
+    /// The body returned by a `static` handler
+    #[serde(default)]
+    pub response_content: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+impl HttpHandler {
+    fn options(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let HttpHandler {
+            handler_type: _,
+            query,
+            response_content,
+            content_type,
+        } = self;
+        [
+            ("query", query),
+            ("response_content", response_content),
+            ("content_type", content_type),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+    }
+}
+
+impl HttpHandlerRule {
+    pub fn to_node(&self) -> ConfigNode {
+        let HttpHandlerRule { url, methods, handler } = self;
+        let methods =
+            (!methods.is_empty()).then(|| ConfigNode::value(methods.join(",")));
+        let handler = handler.options().fold(
+            ConfigNode::map().with_value("type", &handler.handler_type),
+            |node, (name, value)| node.with_value(name, value),
+        );
+        ConfigNode::map()
+            .with_value("url", url)
+            .with_opt("methods", methods)
+            .with("handler", handler)
+    }
+}
+
+/// A map of every setting that has a value
+///
+/// Unset settings are left out, so ClickHouse's default applies to them.
+fn optional_settings(settings: &[(&str, Option<String>)]) -> ConfigNode {
+    settings.iter().fold(ConfigNode::map(), |node, (name, value)| {
+        node.with_opt(*name, value.as_ref().map(ConfigNode::value))
+    })
+}
+
+/// Server-wide caps on memory and background work
+///
+/// ClickHouse's defaults assume it has the machine to itself, so these are
+/// useful when running many servers on one host.
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+pub struct ResourceLimits {
+    /// Maximum memory the server may use, in bytes
+    pub max_server_memory_usage: Option<u64>,
+    /// Number of threads performing merges and mutations
+    pub background_pool_size: Option<u64>,
+    /// Size of the cache of MergeTree marks, in bytes
+    pub mark_cache_size: Option<u64>,
+}
+
+impl ResourceLimits {
+    fn settings(&self) -> [(&'static str, Option<String>); 3] {
+        let ResourceLimits {
+            max_server_memory_usage,
+            background_pool_size,
+            mark_cache_size,
+        } = self;
+        [
+            (
+                "max_server_memory_usage",
+                max_server_memory_usage.map(|v| v.to_string()),
+            ),
+            (
+                "background_pool_size",
+                background_pool_size.map(|v| v.to_string()),
+            ),
+            ("mark_cache_size", mark_cache_size.map(|v| v.to_string())),
+        ]
+    }
+
+    pub fn to_node(&self) -> ConfigNode {
+        optional_settings(&self.settings())
+    }
+}
+
+/// Server-wide concurrency and memory locking settings
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+pub struct RuntimeTuning {
+    /// Maximum number of threads in the global thread pool
+    pub max_thread_pool_size: Option<u64>,
+    /// Maximum number of threads doing IO, e.g. reading from S3
+    pub max_io_thread_pool_size: Option<u64>,
+    /// Lock the server's executable in memory so it is never paged out
+    pub mlock_executable: Option<bool>,
+}
+
+impl RuntimeTuning {
+    fn settings(&self) -> [(&'static str, Option<String>); 3] {
+        let RuntimeTuning {
+            max_thread_pool_size,
+            max_io_thread_pool_size,
+            mlock_executable,
+        } = self;
+        [
+            (
+                "max_thread_pool_size",
+                max_thread_pool_size.map(|v| v.to_string()),
+            ),
+            (
+                "max_io_thread_pool_size",
+                max_io_thread_pool_size.map(|v| v.to_string()),
+            ),
+            ("mlock_executable", mlock_executable.map(|v| v.to_string())),
+        ]
+    }
+
+    pub fn to_node(&self) -> ConfigNode {
+        optional_settings(&self.settings())
+    }
+}
+
+/// A disk that MergeTree tables can store parts on
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct DiskConfig {
+    pub name: String,
+    #[schemars(schema_with = "path_schema")]
+    pub path: Utf8PathBuf,
+}
+
+/// Parses `<name>=<path>`
+impl FromStr for DiskConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <name>=<path>, got {s:?}"))?;
+        Ok(DiskConfig { name: name.to_string(), path: path.into() })
+    }
+}
+
+/// A named volume of a storage policy, made up of one or more disks
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct VolumeConfig {
+    pub name: String,
+    pub disks: Vec<String>,
+}
+
+/// A storage policy: an ordered list of volumes that parts move between
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    pub name: String,
+    pub volumes: Vec<VolumeConfig>,
+}
+
+/// Parses `<name>=<volume>:<disk>[+<disk>...][,<volume>:<disk>...]`
+impl FromStr for PolicyConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "expected <name>=<volume>:<disk>[+<disk>...][,...], got {s:?}"
+            )
+        };
+        let (name, volumes) = s.split_once('=').ok_or_else(err)?;
+        let volumes = volumes
+            .split(',')
+            .map(|v| {
+                let (name, disks) = v.split_once(':').ok_or_else(err)?;
+                let disks = disks.split('+').map(str::to_string).collect();
+                Ok(VolumeConfig { name: name.to_string(), disks })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(PolicyConfig { name: name.to_string(), volumes })
+    }
+}
+
+/// Disks and storage policies for tiered storage
+///
+/// When empty, nothing is rendered and ClickHouse uses its single default
+/// disk at `<path>`.
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+pub struct StorageConfig {
+    pub disks: Vec<DiskConfig>,
+    pub policies: Vec<PolicyConfig>,
+}
+
+impl StorageConfig {
+    pub fn is_empty(&self) -> bool {
+        self.disks.is_empty() && self.policies.is_empty()
+    }
+
+    /// Check that disks are uniquely named and policies only use known disks
+    pub fn validate(&self) -> Result<(), String> {
+        let mut names = vec!["default"];
+        for disk in &self.disks {
+            validate_element_name("disk", &disk.name)?;
+            if names.contains(&disk.name.as_str()) {
+                return Err(format!("duplicate disk name: {}", disk.name));
+            }
+            names.push(&disk.name);
+        }
+        for policy in &self.policies {
+            validate_element_name("policy", &policy.name)?;
+            for volume in &policy.volumes {
+                validate_element_name("volume", &volume.name)?;
+                for disk in &volume.disks {
+                    if !names.contains(&disk.as_str()) {
+                        return Err(format!(
+                            "policy {} uses unknown disk: {disk}",
+                            policy.name
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a copy with all disk paths placed below `dir`
+    pub fn resolve(&self, dir: &Utf8Path) -> StorageConfig {
+        let disks = self
+            .disks
+            .iter()
+            .map(|d| DiskConfig {
+                name: d.name.clone(),
+                path: dir.join(&d.path),
+            })
+            .collect();
+        StorageConfig { disks, policies: self.policies.clone() }
+    }
+
+    /// The `storage_configuration` section, or `None` if there is nothing to
+    /// configure
+    pub fn to_node(&self) -> Option<ConfigNode> {
+        if self.is_empty() {
+            return None;
+        }
+        let disks = self.disks.iter().fold(ConfigNode::map(), |node, disk| {
+            // ClickHouse requires disk paths to end with a slash
+            let path = format!("{}/", disk.path);
+            node.with(&disk.name, ConfigNode::map().with_value("path", path))
+        });
+        let policies =
+            self.policies.iter().fold(ConfigNode::map(), |node, policy| {
+                let volumes = policy.volumes.iter().fold(
+                    ConfigNode::map(),
+                    |node, volume| {
+                        let disks = volume
+                            .disks
+                            .iter()
+                            .map(ConfigNode::value)
+                            .collect();
+                        node.with(
+                            &volume.name,
+                            ConfigNode::map()
+                                .with("disk", ConfigNode::Seq(disks)),
+                        )
+                    },
+                );
+                node.with(
+                    &policy.name,
+                    ConfigNode::map().with("volumes", volumes),
+                )
+            });
+        Some(ConfigNode::map().with("disks", disks).with("policies", policies))
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
 pub struct Macros {
     pub shard: u64,
-    pub replica: ServerId,
+    /// The `{replica}` macro, numbered according to a `ReplicaMacroScheme`
+    pub replica: u64,
     pub cluster: String,
 }
 
 impl Macros {
-    pub fn to_xml(&self) -> String {
+    pub fn to_node(&self) -> ConfigNode {
         let Macros { shard, replica, cluster } = self;
-        format!(
-            "
-    <macros>
-        <shard>{shard}</shard>
-        <replica>{replica}</replica>
-        <cluster>{cluster}</cluster>
-    </macros>"
-        )
+        ConfigNode::map()
+            .with_value("shard", shard)
+            .with_value("replica", replica)
+            .with_value("cluster", cluster)
+    }
+
+    pub fn to_xml(&self) -> String {
+        self.to_node().to_xml("macros", 1)
     }
 }
 
+/// How clickhouse servers are split into shards
+///
+/// Servers are assigned to shards round-robin by id, so server `n` belongs to
+/// shard `(n - 1) % num_shards + 1`.
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
-pub struct RemoteServers {
-    pub cluster: String,
-    pub secret: String,
-    pub replicas: Vec<ServerConfig>,
+pub struct ShardTopology {
+    pub num_shards: u64,
+    /// Weights of shards for `Distributed` table inserts. Shards without an
+    /// entry use ClickHouse's default weight of 1.
+    pub weights: BTreeMap<u64, u32>,
+    #[serde(default)]
+    pub replica_macros: ReplicaMacroScheme,
 }
 
-impl RemoteServers {
-    pub fn to_xml(&self) -> String {
-        let RemoteServers { cluster, secret, replicas } = self;
-
-        let mut s = format!(
-            "
-    <remote_servers replace=\"true\">
-        <{cluster}>
-            <secret>{secret}</secret>
-            <shard>
-                <internal_replication>true</internal_replication>"
-        );
+impl Default for ShardTopology {
+    fn default() -> Self {
+        ShardTopology {
+            num_shards: 1,
+            weights: BTreeMap::new(),
+            replica_macros: ReplicaMacroScheme::default(),
+        }
+    }
+}
+
+impl ShardTopology {
+    /// Return the shard that server `id` belongs to
+    pub fn shard_of(&self, id: ServerId) -> u64 {
+        (id.0 - 1) % self.num_shards + 1
+    }
 
-        for r in replicas {
-            let ServerConfig { host, port } = r;
-            s.push_str(&format!(
-                "
-                <replica>
-                    <host>{host}</host>
-                    <port>{port}</port>
-                </replica>"
+    /// Return the `{replica}` macro of server `id`
+    ///
+    /// Per-shard indexes are derived from the id rather than from the servers
+    /// currently in the shard, so that removing a server never renumbers the
+    /// others. Renumbering would move their `ReplicatedMergeTree` paths.
+    pub fn replica_macro(&self, id: ServerId) -> u64 {
+        match self.replica_macros {
+            ReplicaMacroScheme::Global => id.0,
+            ReplicaMacroScheme::PerShard => (id.0 - 1) / self.num_shards + 1,
+        }
+    }
+
+    /// Check that there is at least one shard and all weights refer to one
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_shards == 0 {
+            return Err("there must be at least one shard".to_string());
+        }
+        if let Some(shard) =
+            self.weights.keys().find(|&&s| s == 0 || s > self.num_shards)
+        {
+            return Err(format!(
+                "weight given for shard {shard}, but shards are numbered 1 to {}",
+                self.num_shards
             ));
         }
+        Ok(())
+    }
+}
 
-        s.push_str(&format!(
-            "
-            </shard>
-        </{cluster}>
-    </remote_servers>
-        "
-        ));
+/// How the `{replica}` macro of each server is numbered
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplicaMacroScheme {
+    /// The server id, which is unique across the whole cluster
+    #[default]
+    Global,
+    /// The index of the server within its shard, starting at 1
+    PerShard,
+}
 
-        s
+impl Display for ReplicaMacroScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaMacroScheme::Global => write!(f, "global"),
+            ReplicaMacroScheme::PerShard => write!(f, "per-shard"),
+        }
+    }
+}
+
+impl FromStr for ReplicaMacroScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(ReplicaMacroScheme::Global),
+            "per-shard" => Ok(ReplicaMacroScheme::PerShard),
+            _ => Err(format!("unknown replica macro scheme: {s}")),
+        }
+    }
+}
+
+/// The weight of a single shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardWeight {
+    pub shard: u64,
+    pub weight: u32,
+}
+
+/// Parses `<shard>=<weight>`
+impl FromStr for ShardWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (shard, weight) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <shard>=<weight>, got {s:?}"))?;
+        let shard = shard
+            .parse()
+            .map_err(|e| format!("invalid shard number {shard:?}: {e}"))?;
+        let weight = weight
+            .parse()
+            .map_err(|e| format!("invalid weight {weight:?}: {e}"))?;
+        Ok(ShardWeight { shard, weight })
+    }
+}
+
+/// A shard of the cluster and the replicas that make it up
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct ShardConfig {
+    /// Rendered as `<weight>` only if set
+    pub weight: Option<u32>,
+    pub replicas: Vec<ServerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+pub struct RemoteServers {
+    pub cluster: String,
+    pub secret: String,
+    pub shards: Vec<ShardConfig>,
+}
+
+impl RemoteServers {
+    pub fn to_node(&self) -> ConfigNode {
+        let RemoteServers { cluster, secret, shards } = self;
+        let shards = shards
+            .iter()
+            .map(|shard| {
+                let replicas = shard
+                    .replicas
+                    .iter()
+                    .map(|r| {
+                        let ServerConfig { host, port } = r;
+                        ConfigNode::map()
+                            .with_value(
+                                "host",
+                                format_host(host, HostContext::RemoteServers),
+                            )
+                            .with_value("port", port)
+                    })
+                    .collect();
+                ConfigNode::map()
+                    .with_opt("weight", shard.weight.map(ConfigNode::value))
+                    .with_value("internal_replication", true)
+                    .with("replica", ConfigNode::Seq(replicas))
+            })
+            .collect();
+        let cluster_node = ConfigNode::map()
+            .with_value("secret", secret)
+            .with("shard", ConfigNode::Seq(shards));
+        ConfigNode::map()
+            .with_value("@replace", true)
+            .with(cluster, cluster_node)
+    }
+
+    pub fn to_xml(&self) -> String {
+        self.to_node().to_xml("remote_servers", 1)
     }
 }
 
@@ -235,20 +1195,25 @@ pub struct KeeperConfigsForReplica {
 }
 
 impl KeeperConfigsForReplica {
+    pub fn to_node(&self) -> ConfigNode {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let ServerConfig { host, port } = node;
+                ConfigNode::map()
+                    .with_value(
+                        "host",
+                        format_host(host, HostContext::Zookeeper),
+                    )
+                    .with_value("port", port)
+            })
+            .collect();
+        ConfigNode::map().with("node", ConfigNode::Seq(nodes))
+    }
+
     pub fn to_xml(&self) -> String {
-        let mut s = String::from("    <zookeeper>");
-        for node in &self.nodes {
-            let ServerConfig { host, port } = node;
-            s.push_str(&format!(
-                "
-        <node>
-            <host>{host}</host>
-            <port>{port}</port>
-        </node>",
-            ));
-        }
-        s.push_str("\n    </zookeeper>");
-        s
+        self.to_node().to_xml("zookeeper", 1)
     }
 }
 
@@ -271,19 +1236,18 @@ pub struct LogConfig {
 }
 
 impl LogConfig {
-    pub fn to_xml(&self) -> String {
+    pub fn to_node(&self) -> ConfigNode {
         let LogConfig { level, log, errorlog, size, count } = &self;
-        format!(
-            "
-    <logger>
-        <level>{level}</level>
-        <log>{log}</log>
-        <errorlog>{errorlog}</errorlog>
-        <size>{size}</size>
-        <count>{count}</count>
-    </logger>
-"
-        )
+        ConfigNode::map()
+            .with_value("level", level)
+            .with_value("log", log)
+            .with_value("errorlog", errorlog)
+            .with_value("size", size)
+            .with_value("count", count)
+    }
+
+    pub fn to_xml(&self) -> String {
+        self.to_node().to_xml("logger", 1)
     }
 }
 
@@ -292,6 +1256,36 @@ pub struct KeeperCoordinationSettings {
     pub operation_timeout_ms: u32,
     pub session_timeout_ms: u32,
     pub raft_logs_level: LogLevel,
+    pub raft: KeeperRaftSettings,
+}
+
+/// Raft tunables that trade keeper durability for latency
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize,
+)]
+pub struct KeeperRaftSettings {
+    /// Don't wait for followers to acknowledge a write before replying
+    pub async_replication: Option<bool>,
+    /// Route reads through raft so they are linearizable
+    pub quorum_reads: Option<bool>,
+    /// fsync the raft log on every write
+    pub force_sync: Option<bool>,
+}
+
+impl KeeperRaftSettings {
+    fn settings(&self) -> [(&'static str, Option<String>); 3] {
+        let KeeperRaftSettings { async_replication, quorum_reads, force_sync } =
+            self;
+        [
+            ("async_replication", async_replication.map(|v| v.to_string())),
+            ("quorum_reads", quorum_reads.map(|v| v.to_string())),
+            ("force_sync", force_sync.map(|v| v.to_string())),
+        ]
+    }
+
+    pub fn to_node(&self) -> ConfigNode {
+        optional_settings(&self.settings())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
@@ -300,37 +1294,52 @@ pub struct RaftServers {
 }
 
 impl RaftServers {
-    pub fn to_xml(&self) -> String {
-        let mut s = String::new();
-        for server in &self.servers {
-            let RaftServerConfig { id, hostname, port } = server;
-            s.push_str(&format!(
-                "
-            <server>
-                <id>{id}</id>
-                <hostname>{hostname}</hostname>
-                <port>{port}</port>
-            </server>
-            "
-            ));
-        }
+    /// The `server` entries of the raft configuration
+    pub fn to_node(&self) -> ConfigNode {
+        let servers = self
+            .servers
+            .iter()
+            .map(|server| {
+                let RaftServerConfig { id, hostname, port, priority } = server;
+                let priority = (*priority != DEFAULT_RAFT_PRIORITY)
+                    .then(|| ConfigNode::value(priority));
+                ConfigNode::map()
+                    .with_value("id", id)
+                    .with_value("hostname", hostname)
+                    .with_value("port", port)
+                    .with_opt("priority", priority)
+            })
+            .collect();
+        ConfigNode::Seq(servers)
+    }
 
-        s
+    pub fn to_xml(&self) -> String {
+        self.to_node().to_xml("server", 3)
     }
 }
 
+/// The raft priority ClickHouse keeper uses when none is configured
+pub const DEFAULT_RAFT_PRIORITY: u8 = 1;
+
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
 pub struct RaftServerConfig {
     pub id: KeeperId,
     pub hostname: String,
     pub port: u16,
+    /// Nodes with higher priority are more likely to become leader. A
+    /// priority of 0 means the node never becomes leader. Only rendered if it
+    /// differs from `DEFAULT_RAFT_PRIORITY`.
+    pub priority: u8,
 }
 
 /// Config for an individual Clickhouse Keeper
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
 pub struct KeeperConfig {
     pub logger: LogConfig,
-    pub listen_host: String,
+    /// Each address is rendered as its own `<listen_host>` element
+    pub listen_hosts: Vec<String>,
+    /// Keep starting if some of `listen_hosts` can't be bound
+    pub listen_try: bool,
     pub tcp_port: u16,
     pub server_id: KeeperId,
     #[schemars(schema_with = "path_schema")]
@@ -339,56 +1348,73 @@ pub struct KeeperConfig {
     pub snapshot_storage_path: Utf8PathBuf,
     pub coordination_settings: KeeperCoordinationSettings,
     pub raft_config: RaftServers,
+    /// The four letter word commands the keeper accepts. Rendered only if
+    /// non-empty, otherwise ClickHouse's default list applies.
+    pub four_letter_word_allow_list: Vec<String>,
 }
 
 impl KeeperConfig {
-    pub fn to_xml(&self) -> String {
+    pub fn to_node(&self) -> ConfigNode {
         let KeeperConfig {
             logger,
-            listen_host,
+            listen_hosts,
+            listen_try,
             tcp_port,
             server_id,
             log_storage_path,
             snapshot_storage_path,
             coordination_settings,
             raft_config,
+            four_letter_word_allow_list,
         } = self;
-        let logger = logger.to_xml();
         let KeeperCoordinationSettings {
             operation_timeout_ms,
             session_timeout_ms,
             raft_logs_level,
+            raft,
         } = coordination_settings;
-        let raft_servers = raft_config.to_xml();
-        format!(
-            "
-<clickhouse>
-{logger}
-    <listen_host>{listen_host}</listen_host>
-    <keeper_server>
-        <enable_reconfiguration>false</enable_reconfiguration>
-        <tcp_port>{tcp_port}</tcp_port>
-        <server_id>{server_id}</server_id>
-        <log_storage_path>{log_storage_path}</log_storage_path>
-        <snapshot_storage_path>{snapshot_storage_path}</snapshot_storage_path>
-        <coordination_settings>
-            <operation_timeout_ms>{operation_timeout_ms}</operation_timeout_ms>
-            <session_timeout_ms>{session_timeout_ms}</session_timeout_ms>
-            <raft_logs_level>{raft_logs_level}</raft_logs_level>
-        </coordination_settings>
-        <raft_configuration>
-{raft_servers}
-        </raft_configuration>
-    </keeper_server>
+        let four_letter_words = (!four_letter_word_allow_list.is_empty())
+            .then(|| ConfigNode::value(four_letter_word_allow_list.join(",")));
+        let coordination_settings = ConfigNode::map()
+            .with_value("operation_timeout_ms", operation_timeout_ms)
+            .with_value("session_timeout_ms", session_timeout_ms)
+            .with_value("raft_logs_level", raft_logs_level)
+            .merge(raft.to_node());
+        let keeper_server = ConfigNode::map()
+            .with_value("enable_reconfiguration", false)
+            .with_value("tcp_port", tcp_port)
+            .with_value("server_id", server_id)
+            .with_value("log_storage_path", log_storage_path)
+            .with_value("snapshot_storage_path", snapshot_storage_path)
+            .with_opt("four_letter_word_allow_list", four_letter_words)
+            .with("coordination_settings", coordination_settings)
+            .with(
+                "raft_configuration",
+                ConfigNode::map().with("server", raft_config.to_node()),
+            );
+        ConfigNode::map()
+            .with("logger", logger.to_node())
+            .merge(listen_node(listen_hosts, *listen_try))
+            .with("keeper_server", keeper_server)
+    }
 
-</clickhouse>
-"
-        )
+    pub fn to_xml(&self) -> String {
+        self.to_config(ConfigFormat::Xml)
+    }
+
+    pub fn to_yaml(&self) -> String {
+        self.to_config(ConfigFormat::Yaml)
+    }
+
+    /// Render this config in the given format
+    pub fn to_config(&self, format: ConfigFormat) -> String {
+        self.to_node().render(format)
     }
 }
 
 #[allow(unused)]
 #[derive(Debug, Clone, PartialEq, Eq, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Trace,
     Debug,
@@ -403,3 +1429,115 @@ impl Display for LogLevel {
         write!(f, "{s}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_host_brackets_only_ipv6_in_zookeeper_context() {
+        let cases = [
+            ("::1", "[::1]", "::1"),
+            ("[::1]", "[::1]", "::1"),
+            ("127.0.0.1", "127.0.0.1", "127.0.0.1"),
+            ("keeper.example.com", "keeper.example.com", "keeper.example.com"),
+        ];
+        for (host, zookeeper, remote_servers) in cases {
+            assert_eq!(
+                format_host(host, HostContext::Zookeeper),
+                zookeeper,
+                "{host} in zookeeper context"
+            );
+            assert_eq!(
+                format_host(host, HostContext::RemoteServers),
+                remote_servers,
+                "{host} in remote_servers context"
+            );
+        }
+    }
+
+    #[test]
+    fn config_node_renders_the_same_tree_as_xml_and_yaml() {
+        let node = ConfigNode::map()
+            .with_value("@replace", true)
+            .with_value("name", "a<b")
+            .with(
+                "host",
+                ConfigNode::Seq(vec![
+                    ConfigNode::value("::1"),
+                    ConfigNode::value("127.0.0.1"),
+                ]),
+            )
+            .with(
+                "replica",
+                ConfigNode::Seq(vec![ConfigNode::map()
+                    .with_value("port", 9000)
+                    .with_value("secure", false)]),
+            )
+            .with_opt("unset", None);
+        assert_eq!(
+            node.render(ConfigFormat::Xml),
+            "\
+<clickhouse replace=\"true\">
+    <name>a&lt;b</name>
+    <host>::1</host>
+    <host>127.0.0.1</host>
+    <replica>
+        <port>9000</port>
+        <secure>false</secure>
+    </replica>
+</clickhouse>
+"
+        );
+        assert_eq!(
+            node.render(ConfigFormat::Yaml),
+            "\
+\"@replace\": true
+name: \"a<b\"
+host:
+    - \"::1\"
+    - \"127.0.0.1\"
+replica:
+    - port: 9000
+      secure: false
+"
+        );
+    }
+
+    #[test]
+    fn http_handler_rule_escapes_user_strings() {
+        let rule = HttpHandlerRule {
+            url: "/a&b".to_string(),
+            methods: vec!["GET<".to_string(), "POST".to_string()],
+            handler: HttpHandler {
+                handler_type: "static>".to_string(),
+                query: None,
+                response_content: Some("<b>hi</b>".to_string()),
+                content_type: None,
+            },
+        };
+        let xml = rule.to_node().to_xml("rule", 0);
+        assert!(xml.contains("<url>/a&amp;b</url>"), "{xml}");
+        assert!(xml.contains("<methods>GET&lt;,POST</methods>"), "{xml}");
+        assert!(xml.contains("<type>static&gt;</type>"), "{xml}");
+        assert!(
+            xml.contains("<response_content>&lt;b&gt;hi&lt;/b&gt;"),
+            "{xml}"
+        );
+    }
+
+    #[test]
+    fn storage_names_must_be_element_names() {
+        let storage = |disk: &str| StorageConfig {
+            disks: vec![DiskConfig {
+                name: disk.to_string(),
+                path: "d".into(),
+            }],
+            policies: vec![],
+        };
+        assert!(storage("cold_disk-1.a").validate().is_ok());
+        for bad in ["", "1disk", "a b", "a<b", "a:b"] {
+            assert!(storage(bad).validate().is_err(), "{bad:?}");
+        }
+    }
+}
@@ -2,13 +2,22 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::POLL_INTERVAL;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
-use std::process::Stdio;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
 use tokio::process::Command;
 
+/// The four letter word commands `KeeperClient` issues
+///
+/// ClickHouse allows these by default. A custom allow list in generated
+/// keeper configs always includes them, so that the client works against any
+/// keeper clickward deploys.
+pub const FOUR_LETTER_WORDS: &[&str] = &["mntr", "srvr", "isro"];
+
 #[derive(Error, Debug)]
 pub enum KeeperError {
     #[error("no config present")]
@@ -22,13 +31,186 @@ pub enum KeeperError {
 
     #[error("query error: query = {query}, error = {error}")]
     Query { query: String, error: String },
+
+    #[error("clickhouse keeper-client failed ({status}): {stderr}")]
+    BinaryFailed { status: ExitStatus, stderr: String },
+
+    #[error("not synced with the leader after {timeout:?} (state: {state})")]
+    NotSynced { timeout: Duration, state: String },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KeeperConfig {
     pub addr: String,
 }
 
+/// The output of the `mntr` four letter word command
+///
+/// All values reported by keeper are kept as strings. Typed accessors are
+/// provided for the ones clickward uses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MntrStats {
+    pub values: BTreeMap<String, String>,
+}
+
+impl MntrStats {
+    /// The raft role of the keeper: `leader`, `follower` or `standalone`
+    pub fn server_state(&self) -> Option<&str> {
+        self.values.get("zk_server_state").map(|s| s.as_str())
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.server_state() == Some("leader")
+    }
+
+    /// The number of followers in sync with the leader
+    ///
+    /// This is only reported by the leader.
+    pub fn synced_followers(&self) -> Option<u64> {
+        self.values.get("zk_synced_followers").and_then(|s| s.parse().ok())
+    }
+
+    /// The number of followers connected to the leader
+    ///
+    /// This is only reported by the leader.
+    pub fn followers(&self) -> Option<u64> {
+        self.values.get("zk_followers").and_then(|s| s.parse().ok())
+    }
+
+    /// Return true if the keeper is caught up with the leader
+    ///
+    /// A leader is synced once all of its followers are. A follower is synced
+    /// once it has committed everything the leader told it to. Older keepers
+    /// don't report commit indexes, in which case being a follower at all is
+    /// the best we can tell.
+    pub fn is_synced(&self) -> bool {
+        self.is_synced_ignoring(0)
+    }
+
+    /// Like `is_synced`, but a leader doesn't wait for `stopped` followers
+    /// that are known to be down, e.g. disabled keepers
+    pub fn is_synced_ignoring(&self, stopped: u64) -> bool {
+        let num = |key: &str| -> Option<u64> {
+            self.values.get(key).and_then(|s| s.parse().ok())
+        };
+        match self.server_state() {
+            Some("standalone") => true,
+            Some("leader") => {
+                match (self.synced_followers(), self.followers()) {
+                    (Some(synced), Some(followers)) => {
+                        synced + stopped >= followers
+                    }
+                    _ => true,
+                }
+            }
+            Some("follower") => match (
+                num("zk_last_committed_log_idx"),
+                num("zk_target_committed_log_idx"),
+            ) {
+                (Some(last), Some(target)) => last >= target,
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The raft role of a keeper, as reported by the `srvr` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeeperMode {
+    Leader,
+    Follower,
+    Observer,
+    Standalone,
+}
+
+/// Connection level statistics from the `srvr` four letter word command
+///
+/// Fields that a keeper doesn't report, which can happen for a standalone
+/// (single node) keeper, are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SrvrStats {
+    pub version: Option<String>,
+    pub latency_min: Option<u64>,
+    pub latency_avg: Option<u64>,
+    pub latency_max: Option<u64>,
+    pub received: Option<u64>,
+    pub sent: Option<u64>,
+    pub connections: Option<u64>,
+    pub outstanding: Option<u64>,
+    pub zxid: Option<u64>,
+    pub mode: KeeperMode,
+    pub node_count: Option<u64>,
+}
+
+impl SrvrStats {
+    fn parse(output: &str) -> Result<SrvrStats, KeeperError> {
+        let mut fields = BTreeMap::new();
+        for line in output.lines().filter(|l| !l.is_empty()) {
+            let (key, value) =
+                line.split_once(':').ok_or(KeeperError::UnexpectedResponse)?;
+            fields.insert(key.trim(), value.trim());
+        }
+        let num = |key: &str| -> Result<Option<u64>, KeeperError> {
+            fields
+                .get(key)
+                .map(|v| v.parse().map_err(|_| KeeperError::UnexpectedResponse))
+                .transpose()
+        };
+
+        let (latency_min, latency_avg, latency_max) =
+            match fields.get("Latency min/avg/max") {
+                Some(latency) => {
+                    let parsed: Vec<u64> = latency
+                        .split('/')
+                        .map(|v| v.parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| KeeperError::UnexpectedResponse)?;
+                    let [min, avg, max] = parsed[..] else {
+                        return Err(KeeperError::UnexpectedResponse);
+                    };
+                    (Some(min), Some(avg), Some(max))
+                }
+                None => (None, None, None),
+            };
+
+        // Newer keepers report the zxid in hex, older ones in decimal
+        let zxid = fields
+            .get("Zxid")
+            .map(|v| match v.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => v.parse(),
+            })
+            .transpose()
+            .map_err(|_| KeeperError::UnexpectedResponse)?;
+
+        let mode = match fields.get("Mode") {
+            Some(&"leader") => KeeperMode::Leader,
+            Some(&"follower") => KeeperMode::Follower,
+            Some(&"observer") => KeeperMode::Observer,
+            Some(&"standalone") => KeeperMode::Standalone,
+            _ => return Err(KeeperError::UnexpectedResponse),
+        };
+
+        Ok(SrvrStats {
+            version: fields
+                .get("ClickHouse Keeper version")
+                .map(|v| v.to_string()),
+            latency_min,
+            latency_avg,
+            latency_max,
+            received: num("Received")?,
+            sent: num("Sent")?,
+            connections: num("Connections")?,
+            outstanding: num("Outstanding")?,
+            zxid,
+            mode,
+            node_count: num("Node count")?,
+        })
+    }
+}
+
 /// A client for interacting with keeper instances
 #[derive(Debug, Clone)]
 pub struct KeeperClient {
@@ -68,11 +250,82 @@ impl KeeperClient {
         Ok(config)
     }
 
+    /// Retrieve monitoring stats via the `mntr` four letter word command
+    pub async fn mntr(&self) -> Result<MntrStats, KeeperError> {
+        let output = self.query("flwc mntr").await?;
+        let mut values = BTreeMap::new();
+        for line in output.lines().filter(|l| !l.is_empty()) {
+            let (key, value) =
+                line.split_once('\t').ok_or(KeeperError::UnexpectedResponse)?;
+            values.insert(key.to_string(), value.trim().to_string());
+        }
+        if values.is_empty() {
+            return Err(KeeperError::UnexpectedResponse);
+        }
+        Ok(MntrStats { values })
+    }
+
+    /// Retrieve connection and latency stats via the `srvr` four letter word
+    /// command
+    pub async fn srvr(&self) -> Result<SrvrStats, KeeperError> {
+        let output = self.query("flwc srvr").await?;
+        SrvrStats::parse(&output)
+    }
+
+    /// Return true if the keeper is in read-only mode, via the `isro` four
+    /// letter word command
+    ///
+    /// A keeper may be read-only temporarily, e.g. while it has lost contact
+    /// with the quorum during reconfiguration.
+    pub async fn is_read_only(&self) -> Result<bool, KeeperError> {
+        let output = self.query("flwc isro").await?;
+        match output.trim() {
+            "ro" => Ok(true),
+            "rw" => Ok(false),
+            _ => Err(KeeperError::UnexpectedResponse),
+        }
+    }
+
+    /// Wait until the keeper is caught up with the leader
+    ///
+    /// Reads from a lagging follower, e.g. of `/keeper/config` right after a
+    /// membership change, can be stale. Waiting for this first makes any node
+    /// give an authoritative answer.
+    pub async fn wait_synced(
+        &self,
+        timeout: Duration,
+    ) -> Result<MntrStats, KeeperError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let res = self.mntr().await;
+            if let Ok(stats) = &res {
+                if stats.is_synced() {
+                    return res;
+                }
+            }
+            if Instant::now() >= deadline {
+                let stats = res?;
+                return Err(KeeperError::NotSynced {
+                    timeout,
+                    state: stats
+                        .server_state()
+                        .unwrap_or("unknown")
+                        .to_string(),
+                });
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
     async fn query(&self, query: &str) -> Result<String, KeeperError> {
-        let mut child = Command::new("clickhouse")
+        let host = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        };
+        let output = Command::new("clickhouse")
             .arg("keeper-client")
             .arg("--host")
-            .arg(format!("[{}]", self.addr.ip().to_string()))
+            .arg(host)
             .arg("--port")
             .arg(self.addr.port().to_string())
             .arg("--query")
@@ -80,17 +333,19 @@ impl KeeperClient {
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()?;
+            .output()
+            .await?;
 
-        let mut stderr = child.stderr.take().unwrap();
-        let mut error = String::new();
-        stderr.read_to_string(&mut error).await?;
+        let error = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if !output.status.success() {
+            return Err(KeeperError::BinaryFailed {
+                status: output.status,
+                stderr: error,
+            });
+        }
         if !error.is_empty() {
             return Err(KeeperError::Query { query: query.to_string(), error });
         }
-        let mut stdout = child.stdout.take().unwrap();
-        let mut output = String::new();
-        stdout.read_to_string(&mut output).await?;
-        Ok(output)
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 }
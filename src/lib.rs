@@ -2,32 +2,102 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use derive_more::{Add, AddAssign, Display, From};
+use derive_more::{Add, AddAssign, Display, From, FromStr};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::Write;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use std::process::{Command, Stdio};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub mod config;
 use config::*;
 
 mod keeper;
-pub use keeper::{KeeperClient, KeeperError};
+pub use keeper::{
+    KeeperClient, KeeperError, KeeperMode, MntrStats, SrvrStats,
+    FOUR_LETTER_WORDS,
+};
+
+mod server;
+pub use server::{ServerClient, ServerError};
+
+mod spec;
+pub use spec::DeploymentSpec;
 
 /// We put things in a subdirectory of the user path for easy cleanup
 pub const DEPLOYMENT_DIR: &str = "deployment";
 
 /// The name of the file where `ClickwardMetadata` lives. This is *always*
-/// directly below <path>/deployment.
+/// directly below <path>/deployment, or <path>/deployment/<name> for named
+/// deployments.
 pub const CLICKWARD_META_FILENAME: &str = "clickward-metadata.json";
 
 const MISSING_META: &str = "No deployment found: Is your path correct?";
 
+/// How long to wait for the keeper leader to become writable before a
+/// membership change
+const KEEPER_WRITABLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a newly started keeper may take to start serving
+const KEEPER_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to poll nodes while waiting on them
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a killed node may take to exit
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a single `/ping` of a clickhouse server may take
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The shared secret for inter-server queries if none is configured
+pub const DEFAULT_CLUSTER_SECRET: &str = "some-unique-value";
+
+/// How far the ports of a named deployment are shifted at a time to keep them
+/// clear of the other deployments under the same path
+const NAMED_PORT_STEP: u16 = 100;
+
+/// Errors returned by clickward that callers may want to handle specifically
+#[derive(Error, Debug)]
+pub enum ClickwardError {
+    #[error("failed to read {path}: {err}")]
+    MetadataIo {
+        path: Utf8PathBuf,
+        #[source]
+        err: std::io::Error,
+    },
+
+    #[error("corrupt metadata in {path}: {err}")]
+    CorruptMetadata {
+        path: Utf8PathBuf,
+        #[source]
+        err: serde_json::Error,
+    },
+
+    #[error("{0} was never part of this deployment")]
+    NeverExisted(NodeRef),
+
+    #[error("{0} was already removed")]
+    AlreadyRemoved(NodeRef),
+
+    #[error("{0} is already part of this deployment")]
+    AlreadyInUse(NodeRef),
+
+    #[error(
+        "metadata was recovered and may not match the node configs: accept \
+         it before regenerating configs"
+    )]
+    RecoveredMetadata,
+}
+
 /// A unique ID for a clickhouse keeper
 #[derive(
     Debug,
@@ -41,6 +111,7 @@ const MISSING_META: &str = "No deployment found: Is your path correct?";
     Add,
     AddAssign,
     Display,
+    FromStr,
     JsonSchema,
     Serialize,
     Deserialize,
@@ -60,25 +131,133 @@ pub struct KeeperId(pub u64);
     Add,
     AddAssign,
     Display,
+    FromStr,
     JsonSchema,
     Serialize,
     Deserialize,
 )]
 pub struct ServerId(pub u64);
 
+/// The most ids a single range given to `parse_id_set` may cover
+const MAX_ID_RANGE_LEN: u64 = 1024;
+
+/// Parse a set of ids given as a comma separated list of ids and inclusive
+/// ranges, e.g. `2-5,7` for `{2, 3, 4, 5, 7}`
+pub fn parse_id_set<T: From<u64> + Ord>(
+    s: &str,
+) -> Result<BTreeSet<T>, String> {
+    let parse = |n: &str| {
+        n.trim().parse::<u64>().map_err(|e| format!("invalid id {n:?}: {e}"))
+    };
+    let mut ids = BTreeSet::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(format!("invalid id range {part:?}"));
+                }
+                if end - start >= MAX_ID_RANGE_LEN {
+                    return Err(format!(
+                        "id range {part:?} covers more than \
+                         {MAX_ID_RANGE_LEN} ids"
+                    ));
+                }
+                ids.extend((start..=end).map(T::from));
+            }
+            None => {
+                ids.insert(T::from(parse(part)?));
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// A reference to a single node of a deployment
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRef {
+    Keeper(KeeperId),
+    Server(ServerId),
+}
+
+impl std::fmt::Display for NodeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeRef::Keeper(id) => write!(f, "keeper {id}"),
+            NodeRef::Server(id) => write!(f, "clickhouse server {id}"),
+        }
+    }
+}
+
+/// What a port bound by a node is used for
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    JsonSchema,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PortKind {
+    Keeper,
+    Raft,
+    ClickhouseTcp,
+    ClickhouseHttp,
+    ClickhouseInterserverHttp,
+    Prometheus,
+}
+
+impl std::fmt::Display for PortKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PortKind::Keeper => "keeper",
+            PortKind::Raft => "raft",
+            PortKind::ClickhouseTcp => "clickhouse tcp",
+            PortKind::ClickhouseHttp => "clickhouse http",
+            PortKind::ClickhouseInterserverHttp => {
+                "clickhouse interserver http"
+            }
+            PortKind::Prometheus => "prometheus",
+        };
+        write!(f, "{s}")
+    }
+}
+
 pub const DEFAULT_BASE_PORTS: BasePorts = BasePorts {
     keeper: 20000,
     raft: 21000,
     clickhouse_tcp: 22000,
     clickhouse_http: 23000,
     clickhouse_interserver_http: 24000,
+    prometheus: 25000,
 };
 
 // A configuration for a given clickward deployment
+#[derive(Debug, Clone)]
 pub struct DeploymentConfig {
     pub path: Utf8PathBuf,
     pub base_ports: BasePorts,
     pub cluster_name: String,
+    /// The name of a named deployment, which is generated with its own ports
+    /// and secret
+    pub name: Option<String>,
 }
 
 impl DeploymentConfig {
@@ -86,22 +265,249 @@ impl DeploymentConfig {
         path: Utf8PathBuf,
         cluster_name: S,
     ) -> DeploymentConfig {
-        let path = path.join(DEPLOYMENT_DIR);
+        let path = deployment_dir(&path);
         DeploymentConfig {
             path,
             base_ports: DEFAULT_BASE_PORTS,
             cluster_name: cluster_name.into(),
+            name: None,
+        }
+    }
+
+    /// Replace the ports and cluster name with those recorded in `settings`,
+    /// if any
+    fn apply_settings(&mut self, settings: &ConfigSettings) {
+        if let Some(base_ports) = &settings.base_ports {
+            self.base_ports = base_ports.clone();
+        }
+        if let Some(cluster_name) = &settings.cluster_name {
+            self.cluster_name = cluster_name.clone();
+        }
+    }
+
+    /// Create a config for the deployment called `name` under `path`
+    ///
+    /// Named deployments live in <path>/deployment/<name>, which allows
+    /// several independent deployments to be managed under one path. When
+    /// generated, each gets its own secret and ports that don't overlap those
+    /// of the other deployments.
+    pub fn new_named_with_default_ports<S: Into<String>>(
+        path: Utf8PathBuf,
+        name: &str,
+        cluster_name: S,
+    ) -> Result<DeploymentConfig> {
+        validate_deployment_name(name)?;
+        let mut config = Self::new_with_default_ports(path, cluster_name);
+        config.path.push(name);
+        config.name = Some(name.to_string());
+        Ok(config)
+    }
+}
+
+/// Ensure a deployment name is usable as a directory below `DEPLOYMENT_DIR`
+/// and as a cluster name
+fn validate_deployment_name(name: &str) -> Result<()> {
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_".contains(c))
+    {
+        bail!(
+            "Invalid deployment name: {name:?}: names must start with a \
+             letter and contain only letters, digits, '-' and '_'"
+        );
+    }
+    // `deploy` treats directories with these prefixes as nodes
+    if name.starts_with("keeper") || name.starts_with("clickhouse") {
+        bail!(
+            "Invalid deployment name: {name:?}: names may not start with \
+             \"keeper\" or \"clickhouse\""
+        );
+    }
+    Ok(())
+}
+
+/// Return the directory an unnamed deployment rooted at `path` lives in
+pub fn deployment_dir(path: &Utf8Path) -> Utf8PathBuf {
+    path.join(DEPLOYMENT_DIR)
+}
+
+/// Return the names of all named deployments under `path`
+pub fn named_deployments(path: &Utf8Path) -> Result<Vec<String>> {
+    let dir = deployment_dir(path);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut names = vec![];
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.path().join(CLICKWARD_META_FILENAME).is_file() {
+            names.push(entry.file_name().to_string());
         }
     }
+    names.sort();
+    Ok(names)
 }
 
 // Port allocation used for config generation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BasePorts {
     pub keeper: u16,
     pub raft: u16,
     pub clickhouse_tcp: u16,
     pub clickhouse_http: u16,
     pub clickhouse_interserver_http: u16,
+    /// Only used if the Prometheus endpoint is enabled
+    #[serde(default = "default_prometheus_base_port")]
+    pub prometheus: u16,
+}
+
+impl BasePorts {
+    /// Return these ports shifted up by `offset`, or `None` if any of them
+    /// would exceed the port range
+    pub fn shifted(&self, offset: u16) -> Option<BasePorts> {
+        Some(BasePorts {
+            keeper: self.keeper.checked_add(offset)?,
+            raft: self.raft.checked_add(offset)?,
+            clickhouse_tcp: self.clickhouse_tcp.checked_add(offset)?,
+            clickhouse_http: self.clickhouse_http.checked_add(offset)?,
+            clickhouse_interserver_http: self
+                .clickhouse_interserver_http
+                .checked_add(offset)?,
+            prometheus: self.prometheus.checked_add(offset)?,
+        })
+    }
+}
+
+/// Settings that control how node configuration is generated
+///
+/// These are persisted in `ClickwardMetadata` so that configs regenerated when
+/// adding or removing nodes match the ones originally generated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigSettings {
+    /// The file format of generated configs
+    #[serde(default)]
+    pub format: ConfigFormat,
+
+    /// Asynchronous insert settings. Async inserts are disabled if unset.
+    #[serde(default)]
+    pub async_insert: Option<AsyncInsertConfig>,
+
+    /// Extra disks and storage policies. Disk paths are relative to each
+    /// clickhouse server's directory.
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Leave the `opentelemetry_span_log` table out of server configs
+    #[serde(default)]
+    pub omit_span_log: bool,
+
+    /// Raft priorities of keepers that don't use `DEFAULT_RAFT_PRIORITY`
+    #[serde(default)]
+    pub keeper_priorities: BTreeMap<KeeperId, u8>,
+
+    /// The shared secret used for inter-server queries within the cluster
+    #[serde(default = "default_cluster_secret")]
+    pub secret: String,
+
+    /// The log level of all keepers and servers
+    #[serde(default = "default_log_level")]
+    pub log_level: LogLevel,
+
+    /// Give each node its own IPv4 loopback address instead of sharing `::1`
+    #[serde(default)]
+    pub loopback_per_node: bool,
+
+    /// Addresses every node listens on. If empty, each node listens only on
+    /// its own address.
+    #[serde(default)]
+    pub listen_hosts: Vec<String>,
+
+    /// Let nodes start even if some of their listen addresses can't be bound
+    #[serde(default)]
+    pub listen_try: bool,
+
+    /// How clickhouse servers are split into shards
+    #[serde(default)]
+    pub shards: ShardTopology,
+
+    /// Memory and thread limits of every clickhouse server
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+
+    /// The http path clickhouse servers serve Prometheus metrics on. The
+    /// endpoint is disabled if unset.
+    #[serde(default)]
+    pub prometheus_endpoint: Option<String>,
+
+    /// Raft durability settings of every keeper
+    #[serde(default)]
+    pub keeper_raft: KeeperRaftSettings,
+
+    /// Seconds clickhouse servers keep idle http connections open
+    #[serde(default)]
+    pub keep_alive_timeout: Option<u32>,
+
+    /// Custom http handlers of every clickhouse server
+    #[serde(default)]
+    pub http_handlers: Vec<HttpHandlerRule>,
+
+    /// Thread pool sizes and memory locking of every clickhouse server
+    #[serde(default)]
+    pub runtime_tuning: RuntimeTuning,
+
+    /// The ports nodes are allocated from. These take precedence over the
+    /// ports in a `DeploymentConfig` used to load the deployment.
+    #[serde(default)]
+    pub base_ports: Option<BasePorts>,
+
+    /// The name of the clickhouse cluster. This takes precedence over the
+    /// name in a `DeploymentConfig` used to load the deployment.
+    #[serde(default)]
+    pub cluster_name: Option<String>,
+
+    /// The four letter word commands keepers accept. If empty, ClickHouse's
+    /// default list applies. Otherwise the words clickward issues itself are
+    /// always added.
+    #[serde(default)]
+    pub four_letter_word_allow_list: Vec<String>,
+}
+
+impl Default for ConfigSettings {
+    fn default() -> Self {
+        ConfigSettings {
+            format: ConfigFormat::default(),
+            async_insert: None,
+            storage: StorageConfig::default(),
+            omit_span_log: false,
+            keeper_priorities: BTreeMap::new(),
+            secret: default_cluster_secret(),
+            log_level: default_log_level(),
+            loopback_per_node: false,
+            listen_hosts: Vec::new(),
+            listen_try: false,
+            shards: ShardTopology::default(),
+            resource_limits: ResourceLimits::default(),
+            prometheus_endpoint: None,
+            keeper_raft: KeeperRaftSettings::default(),
+            keep_alive_timeout: None,
+            http_handlers: Vec::new(),
+            runtime_tuning: RuntimeTuning::default(),
+            base_ports: None,
+            cluster_name: None,
+            four_letter_word_allow_list: Vec::new(),
+        }
+    }
+}
+
+fn default_cluster_secret() -> String {
+    DEFAULT_CLUSTER_SECRET.to_string()
+}
+
+fn default_prometheus_base_port() -> u16 {
+    DEFAULT_BASE_PORTS.prometheus
+}
+
+fn default_log_level() -> LogLevel {
+    LogLevel::Trace
 }
 
 /// Metadata stored for use by clickward
@@ -125,12 +531,47 @@ pub struct ClickwardMetadata {
     /// The maximum allocated clickhouse server id so far
     /// We only ever increment when adding a new id.
     pub max_server_id: ServerId,
+
+    /// Settings used to generate node configs
+    #[serde(default)]
+    pub settings: ConfigSettings,
+
+    /// IDs of keepers that are part of the cluster but deliberately stopped
+    /// and skipped by `deploy`
+    #[serde(default)]
+    pub disabled_keepers: BTreeSet<KeeperId>,
+
+    /// IDs of clickhouse servers that are part of the cluster but
+    /// deliberately stopped and skipped by `deploy`
+    #[serde(default)]
+    pub disabled_servers: BTreeSet<ServerId>,
+
+    /// Set if this metadata was rebuilt by `Deployment::recover_metadata`
+    ///
+    /// Settings that can't be read back from node configs have their default
+    /// values, so configs aren't regenerated from recovered metadata until it
+    /// has been accepted with `Deployment::accept_recovered_metadata`.
+    #[serde(default)]
+    pub recovered: bool,
 }
 
 impl ClickwardMetadata {
     pub fn new(
         keeper_ids: BTreeSet<KeeperId>,
         replica_ids: BTreeSet<ServerId>,
+    ) -> ClickwardMetadata {
+        ClickwardMetadata::with_settings(
+            keeper_ids,
+            replica_ids,
+            ConfigSettings::default(),
+        )
+    }
+
+    /// Like `new`, but for configs generated with non-default `settings`
+    pub fn with_settings(
+        keeper_ids: BTreeSet<KeeperId>,
+        replica_ids: BTreeSet<ServerId>,
+        settings: ConfigSettings,
     ) -> ClickwardMetadata {
         let max_keeper_id = *keeper_ids.last().unwrap();
         let max_replica_id = *replica_ids.last().unwrap();
@@ -139,6 +580,30 @@ impl ClickwardMetadata {
             max_keeper_id,
             server_ids: replica_ids,
             max_server_id: max_replica_id,
+            settings,
+            disabled_keepers: BTreeSet::new(),
+            disabled_servers: BTreeSet::new(),
+            recovered: false,
+        }
+    }
+
+    /// Fail unless `node` is currently part of the deployment, telling apart
+    /// ids that were never allocated from those of removed nodes
+    pub fn check_member(&self, node: NodeRef) -> Result<(), ClickwardError> {
+        let (id, is_member, max_id) = match node {
+            NodeRef::Keeper(id) => {
+                (id.0, self.keeper_ids.contains(&id), self.max_keeper_id.0)
+            }
+            NodeRef::Server(id) => {
+                (id.0, self.server_ids.contains(&id), self.max_server_id.0)
+            }
+        };
+        if is_member {
+            Ok(())
+        } else if id == 0 || id > max_id {
+            Err(ClickwardError::NeverExisted(node))
+        } else {
+            Err(ClickwardError::AlreadyRemoved(node))
         }
     }
 
@@ -148,10 +613,43 @@ impl ClickwardMetadata {
         self.max_keeper_id
     }
 
-    pub fn remove_keeper(&mut self, id: KeeperId) -> Result<()> {
-        let was_removed = self.keeper_ids.remove(&id);
-        if !was_removed {
-            bail!("No such keeper: {id}");
+    /// Add back a keeper id that was previously removed
+    pub fn add_keeper_with_id(
+        &mut self,
+        id: KeeperId,
+    ) -> Result<(), ClickwardError> {
+        let node = NodeRef::Keeper(id);
+        if id.0 == 0 || id > self.max_keeper_id {
+            return Err(ClickwardError::NeverExisted(node));
+        }
+        if !self.keeper_ids.insert(id) {
+            return Err(ClickwardError::AlreadyInUse(node));
+        }
+        Ok(())
+    }
+
+    pub fn remove_keeper(
+        &mut self,
+        id: KeeperId,
+    ) -> Result<(), ClickwardError> {
+        self.check_member(NodeRef::Keeper(id))?;
+        self.keeper_ids.remove(&id);
+        self.settings.keeper_priorities.remove(&id);
+        self.disabled_keepers.remove(&id);
+        Ok(())
+    }
+
+    /// Mark a keeper as disabled, or enabled again
+    pub fn set_keeper_disabled(
+        &mut self,
+        id: KeeperId,
+        disabled: bool,
+    ) -> Result<(), ClickwardError> {
+        self.check_member(NodeRef::Keeper(id))?;
+        if disabled {
+            self.disabled_keepers.insert(id);
+        } else {
+            self.disabled_keepers.remove(&id);
         }
         Ok(())
     }
@@ -162,20 +660,55 @@ impl ClickwardMetadata {
         self.max_server_id
     }
 
-    pub fn remove_server(&mut self, id: ServerId) -> Result<()> {
-        let was_removed = self.server_ids.remove(&id);
-        if !was_removed {
-            bail!("No such replica: {id}");
+    /// Add back a server id that was previously removed
+    pub fn add_server_with_id(
+        &mut self,
+        id: ServerId,
+    ) -> Result<(), ClickwardError> {
+        let node = NodeRef::Server(id);
+        if id.0 == 0 || id > self.max_server_id {
+            return Err(ClickwardError::NeverExisted(node));
+        }
+        if !self.server_ids.insert(id) {
+            return Err(ClickwardError::AlreadyInUse(node));
+        }
+        Ok(())
+    }
+
+    pub fn remove_server(
+        &mut self,
+        id: ServerId,
+    ) -> Result<(), ClickwardError> {
+        self.check_member(NodeRef::Server(id))?;
+        self.server_ids.remove(&id);
+        self.disabled_servers.remove(&id);
+        Ok(())
+    }
+
+    /// Mark a clickhouse server as disabled, or enabled again
+    pub fn set_server_disabled(
+        &mut self,
+        id: ServerId,
+        disabled: bool,
+    ) -> Result<(), ClickwardError> {
+        self.check_member(NodeRef::Server(id))?;
+        if disabled {
+            self.disabled_servers.insert(id);
+        } else {
+            self.disabled_servers.remove(&id);
         }
         Ok(())
     }
 
-    pub fn load(deployment_dir: &Utf8Path) -> Result<ClickwardMetadata> {
+    pub fn load(
+        deployment_dir: &Utf8Path,
+    ) -> Result<ClickwardMetadata, ClickwardError> {
         let path = deployment_dir.join(CLICKWARD_META_FILENAME);
-        let json = std::fs::read_to_string(&path)
-            .with_context(|| format!("failed to read {path}"))?;
-        let meta = serde_json::from_str(&json)?;
-        Ok(meta)
+        let json = std::fs::read_to_string(&path).map_err(|err| {
+            ClickwardError::MetadataIo { path: path.clone(), err }
+        })?;
+        serde_json::from_str(&json)
+            .map_err(|err| ClickwardError::CorruptMetadata { path, err })
     }
 
     pub fn save(&self, deployment_dir: &Utf8Path) -> Result<()> {
@@ -187,12 +720,139 @@ impl ClickwardMetadata {
     }
 }
 
+/// The number of nodes of each kind that are running in a deployment
+///
+/// This is a cheap summary based on pidfile liveness only. It does not
+/// contact any of the nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningCounts {
+    pub keepers_running: usize,
+    pub keepers_total: usize,
+    pub servers_running: usize,
+    pub servers_total: usize,
+}
+
+impl RunningCounts {
+    /// Return true if any node in the deployment is running
+    pub fn any_running(&self) -> bool {
+        self.keepers_running > 0 || self.servers_running > 0
+    }
+}
+
+/// Whether a node's process is running, according to its pidfile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeStatus {
+    Running,
+    Stopped,
+}
+
+/// The status of every node in a deployment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentStatus {
+    pub keepers: BTreeMap<KeeperId, NodeStatus>,
+    pub servers: BTreeMap<ServerId, NodeStatus>,
+}
+
+impl DeploymentStatus {
+    pub fn counts(&self) -> RunningCounts {
+        let running = |s: &&NodeStatus| matches!(s, NodeStatus::Running);
+        RunningCounts {
+            keepers_running: self.keepers.values().filter(running).count(),
+            keepers_total: self.keepers.len(),
+            servers_running: self.servers.values().filter(running).count(),
+            servers_total: self.servers.len(),
+        }
+    }
+
+    /// Return true if a majority of keepers are running
+    pub fn has_keeper_quorum(&self) -> bool {
+        let counts = self.counts();
+        counts.keepers_running > counts.keepers_total / 2
+    }
+}
+
+/// A numeric snapshot of the shape and health of a deployment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentMetrics {
+    pub keepers_total: usize,
+    pub keepers_running: usize,
+    pub servers_total: usize,
+    pub servers_running: usize,
+    /// The current keeper leader, if one could be found
+    pub leader_keeper_id: Option<KeeperId>,
+    /// The number of followers in sync with the leader, as reported by it
+    pub synced_followers: Option<u64>,
+}
+
+/// How a change to cluster membership was applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipChange {
+    /// The deployment was running and the change was applied to the live
+    /// cluster
+    Live,
+
+    /// Nothing was running, so only the configs were regenerated. The change
+    /// takes effect on the next `deploy`.
+    ConfigOnly,
+}
+
+/// What happened to a single node during `teardown`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopOutcome {
+    /// The node was running and has been killed
+    Stopped,
+
+    /// There was no live process for the node
+    WasNotRunning,
+
+    /// The node was running but could not be stopped
+    Failed(String),
+}
+
+/// The outcome of stopping each node of a deployment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeardownReport {
+    pub keepers: BTreeMap<KeeperId, StopOutcome>,
+    pub servers: BTreeMap<ServerId, StopOutcome>,
+}
+
+impl TeardownReport {
+    /// Return true if no node failed to stop
+    pub fn succeeded(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Return every node that failed to stop along with the reason
+    pub fn failures(&self) -> impl Iterator<Item = (NodeRef, &str)> {
+        let keepers =
+            self.keepers.iter().filter_map(|(id, outcome)| match outcome {
+                StopOutcome::Failed(err) => {
+                    Some((NodeRef::Keeper(*id), err.as_str()))
+                }
+                _ => None,
+            });
+        let servers =
+            self.servers.iter().filter_map(|(id, outcome)| match outcome {
+                StopOutcome::Failed(err) => {
+                    Some((NodeRef::Server(*id), err.as_str()))
+                }
+                _ => None,
+            });
+        keepers.chain(servers)
+    }
+}
+
 /// A deployment of Clickhouse servers and Keeper clusters
 ///
 /// This always generates clusters on localhost and is suitable only for testing
 pub struct Deployment {
     config: DeploymentConfig,
     meta: Option<ClickwardMetadata>,
+    print_commands: bool,
+    timings: bool,
 }
 
 impl Deployment {
@@ -205,15 +865,117 @@ impl Deployment {
         Deployment::new(config)
     }
 
+    pub fn new_named_with_default_port_config<S: Into<String>>(
+        path: Utf8PathBuf,
+        name: &str,
+        cluster_name: S,
+    ) -> Result<Deployment> {
+        let config = DeploymentConfig::new_named_with_default_ports(
+            path,
+            name,
+            cluster_name,
+        )?;
+        Ok(Deployment::new(config))
+    }
+
+    /// Create a deployment, treating any failure to load metadata as if no
+    /// deployment had been generated
+    ///
+    /// Use `try_new` to distinguish a missing deployment from a broken one.
     pub fn new(config: DeploymentConfig) -> Deployment {
         let meta = ClickwardMetadata::load(&config.path).ok();
-        Deployment { config, meta }
+        Deployment::from_parts(config, meta)
+    }
+
+    /// Create a deployment, failing if metadata exists but can't be loaded
+    ///
+    /// `meta()` is `None` only if no metadata file exists.
+    pub fn try_new(
+        config: DeploymentConfig,
+    ) -> Result<Deployment, ClickwardError> {
+        let meta = match ClickwardMetadata::load(&config.path) {
+            Ok(meta) => Some(meta),
+            Err(ClickwardError::MetadataIo { err, .. })
+                if err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Deployment::from_parts(config, meta))
+    }
+
+    /// Settings recorded in the metadata of an existing deployment override
+    /// those in `config`
+    fn from_parts(
+        mut config: DeploymentConfig,
+        meta: Option<ClickwardMetadata>,
+    ) -> Deployment {
+        if let Some(meta) = &meta {
+            config.apply_settings(&meta.settings);
+        }
+        Deployment { config, meta, print_commands: false, timings: false }
+    }
+
+    /// Print the command line of every node this deployment spawns
+    pub fn set_print_commands(&mut self, print_commands: bool) {
+        self.print_commands = print_commands;
     }
 
     pub fn meta(&self) -> &Option<ClickwardMetadata> {
         &self.meta
     }
 
+    /// Print how long config generation, process spawning and readiness
+    /// waits take
+    pub fn set_timings(&mut self, timings: bool) {
+        self.timings = timings;
+    }
+
+    fn report_timing(&self, what: &str, start: Instant) {
+        if self.timings {
+            println!("Timing: {what} in {:.1?}", start.elapsed());
+        }
+    }
+
+    /// The effective config of this deployment, including any settings
+    /// recorded in its metadata
+    pub fn config(&self) -> &DeploymentConfig {
+        &self.config
+    }
+
+    /// The directory holding this deployment's nodes and metadata
+    pub fn path(&self) -> &Utf8Path {
+        &self.config.path
+    }
+
+    pub fn cluster_name(&self) -> &str {
+        &self.config.cluster_name
+    }
+
+    pub fn base_ports(&self) -> &BasePorts {
+        &self.config.base_ports
+    }
+
+    /// The format of the generated configs of this deployment
+    fn config_format(&self) -> ConfigFormat {
+        self.meta.as_ref().map(|m| m.settings.format).unwrap_or_default()
+    }
+
+    fn settings(&self) -> ConfigSettings {
+        self.meta.as_ref().map(|m| m.settings.clone()).unwrap_or_default()
+    }
+
+    /// Return the address a given keeper listens on
+    pub fn keeper_ip(&self, id: KeeperId) -> IpAddr {
+        node_ip(&self.settings(), NodeRef::Keeper(id))
+    }
+
+    /// Return the address a given clickhouse server listens on
+    pub fn server_ip(&self, id: ServerId) -> IpAddr {
+        node_ip(&self.settings(), NodeRef::Server(id))
+    }
+
     /// Return the expected clickhouse http port for a given server id
     pub fn http_port(&self, id: ServerId) -> u16 {
         self.config.base_ports.clickhouse_http + id.0 as u16
@@ -224,86 +986,795 @@ impl Deployment {
         self.config.base_ports.clickhouse_tcp + id.0 as u16
     }
 
+    /// Return the port a given server serves Prometheus metrics on, if enabled
+    pub fn prometheus_port(&self, id: ServerId) -> u16 {
+        self.config.base_ports.prometheus + id.0 as u16
+    }
+
+    /// Return the address a given server serves Prometheus metrics on, or
+    /// `None` if the endpoint isn't enabled
+    pub fn prometheus_addr(&self, id: ServerId) -> Option<SocketAddr> {
+        self.settings().prometheus_endpoint.as_ref()?;
+        Some(SocketAddr::new(self.server_ip(id), self.prometheus_port(id)))
+    }
+
     /// Return the expected localhost http addr for a given server id
     pub fn http_addr(&self, id: ServerId) -> SocketAddr {
         let port = self.http_port(id);
-        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+        SocketAddr::new(self.server_ip(id), port)
     }
 
     /// Return the expected localhost native TCP addr for a given server ID.
     pub fn native_addr(&self, id: ServerId) -> SocketAddr {
         let port = self.native_port(id);
-        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+        SocketAddr::new(self.server_ip(id), port)
     }
 
     pub fn keeper_port(&self, id: KeeperId) -> u16 {
         self.config.base_ports.keeper + id.0 as u16
     }
 
+    /// Return every port the nodes of this deployment bind, sorted by port
+    pub fn port_map(&self) -> Result<Vec<(u16, PortKind, NodeRef)>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let ports = &self.config.base_ports;
+        let mut map = vec![];
+        for &id in &meta.keeper_ids {
+            let node = NodeRef::Keeper(id);
+            map.push((ports.keeper + id.0 as u16, PortKind::Keeper, node));
+            map.push((ports.raft + id.0 as u16, PortKind::Raft, node));
+        }
+        for &id in &meta.server_ids {
+            let node = NodeRef::Server(id);
+            for (base, kind) in [
+                (ports.clickhouse_tcp, PortKind::ClickhouseTcp),
+                (ports.clickhouse_http, PortKind::ClickhouseHttp),
+                (
+                    ports.clickhouse_interserver_http,
+                    PortKind::ClickhouseInterserverHttp,
+                ),
+            ] {
+                map.push((base + id.0 as u16, kind, node));
+            }
+            if meta.settings.prometheus_endpoint.is_some() {
+                map.push((
+                    self.prometheus_port(id),
+                    PortKind::Prometheus,
+                    node,
+                ));
+            }
+        }
+        map.sort();
+        Ok(map)
+    }
+
+    /// Return the address a given keeper serves clients on
+    ///
+    /// Fails if the keeper's port would be past the end of the port range.
     pub fn keeper_addr(&self, id: KeeperId) -> Result<SocketAddr> {
-        let port = self.keeper_port(id);
-        let addr: SocketAddr = format!("[::1]:{port}")
-            .parse()
-            .context("failed to create address")?;
-        Ok(addr)
+        let port = u16::try_from(id.0)
+            .ok()
+            .and_then(|offset| {
+                self.config.base_ports.keeper.checked_add(offset)
+            })
+            .with_context(|| format!("Keeper {id} has no valid port"))?;
+        Ok(SocketAddr::new(self.keeper_ip(id), port))
     }
 
-    /// Stop all clickhouse servers and keepers
-    pub fn teardown(&self) -> Result<()> {
-        if let Some(meta) = &self.meta {
-            // We don't keep track of which nodes we already stopped, and so we
-            // allow stopping to fail.
-            for id in &meta.keeper_ids {
-                // TODO: Logging?
-                let _ = self.stop_keeper(*id);
-            }
-            for id in &meta.server_ids {
-                // TODO: Logging?
-                let _ = self.stop_server(*id);
+    /// Return true if the process recorded in the keeper's pidfile is alive
+    pub fn keeper_is_running(&self, id: KeeperId) -> bool {
+        let dir = self.config.path.join(format!("keeper-{id}"));
+        pidfile_is_running(&dir.join("keeper.pid"))
+    }
+
+    /// Return true if the process recorded in the server's pidfile is alive
+    pub fn server_is_running(&self, id: ServerId) -> bool {
+        let dir = self.config.path.join(format!("clickhouse-{id}"));
+        pidfile_is_running(&dir.join("clickhouse.pid"))
+    }
+
+    /// Return whether each keeper and server of this deployment is running
+    pub fn status(&self) -> Result<DeploymentStatus> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let to_status = |running| {
+            if running {
+                NodeStatus::Running
+            } else {
+                NodeStatus::Stopped
             }
-        }
-        Ok(())
+        };
+        let keepers = meta
+            .keeper_ids
+            .iter()
+            .map(|&id| (id, to_status(self.keeper_is_running(id))))
+            .collect();
+        let servers = meta
+            .server_ids
+            .iter()
+            .map(|&id| (id, to_status(self.server_is_running(id))))
+            .collect();
+        Ok(DeploymentStatus { keepers, servers })
     }
 
-    /// Add a node to clickhouse keeper config at all replicas and start the new
-    /// keeper
-    pub fn add_keeper(&mut self) -> Result<()> {
-        let path = &self.config.path;
-        let (new_id, meta) = if let Some(meta) = &mut self.meta {
-            let new_id = meta.add_keeper();
-            println!("Updating config to include new keeper: {new_id}");
-            meta.save(path)?;
-            (new_id, meta.clone())
-        } else {
+    /// Count the running keepers and servers of this deployment
+    ///
+    /// Unlike `status`, this doesn't build a per-node map. It only checks the
+    /// pidfile of each node.
+    pub fn running_counts(&self) -> Result<RunningCounts> {
+        let Some(meta) = &self.meta else {
             bail!(MISSING_META);
         };
+        let keepers_running = meta
+            .keeper_ids
+            .iter()
+            .filter(|&&id| self.keeper_is_running(id))
+            .count();
+        let servers_running = meta
+            .server_ids
+            .iter()
+            .filter(|&&id| self.server_is_running(id))
+            .count();
+        Ok(RunningCounts {
+            keepers_running,
+            keepers_total: meta.keeper_ids.len(),
+            servers_running,
+            servers_total: meta.server_ids.len(),
+        })
+    }
 
-        // We update the new node and start it before the other nodes. It must be online
-        // for reconfiguration to succeed.
-        self.generate_keeper_config(new_id, meta.keeper_ids.clone())?;
-        self.start_keeper(new_id)?;
+    /// Find the current keeper leader by asking each running keeper
+    ///
+    /// Returns `None` if no running keeper reports itself as the leader.
+    pub async fn find_keeper_leader(&self) -> Result<Option<KeeperId>> {
+        Ok(self.find_keeper_leader_stats().await?.map(|(id, _)| id))
+    }
 
-        // Generate new configs for all the other keepers
-        // They will automatically reload them.
-        let mut other_keepers = meta.keeper_ids.clone();
-        other_keepers.remove(&new_id);
+    async fn find_keeper_leader_stats(
+        &self,
+    ) -> Result<Option<(KeeperId, MntrStats)>> {
+        let status = self.status()?;
+        for (id, node_status) in status.keepers {
+            if node_status != NodeStatus::Running {
+                continue;
+            }
+            let zk = KeeperClient::new(self.keeper_addr(id)?);
+            // Keepers that don't respond can't be the leader
+            if let Ok(stats) = zk.mntr().await {
+                if stats.is_leader() {
+                    return Ok(Some((id, stats)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Collect a numeric snapshot of the deployment for monitoring
+    pub async fn metrics(&self) -> Result<DeploymentMetrics> {
+        let counts = self.running_counts()?;
+        let leader = self.find_keeper_leader_stats().await?;
+        let synced_followers =
+            leader.as_ref().and_then(|(_, stats)| stats.synced_followers());
+        Ok(DeploymentMetrics {
+            keepers_total: counts.keepers_total,
+            keepers_running: counts.keepers_running,
+            servers_total: counts.servers_total,
+            servers_running: counts.servers_running,
+            leader_keeper_id: leader.map(|(id, _)| id),
+            synced_followers,
+        })
+    }
+
+    /// Return true if the clickhouse server answers `GET /ping` on its http
+    /// port
+    pub async fn server_is_ready(&self, id: ServerId) -> bool {
+        let ping = ping_server(self.http_addr(id));
+        matches!(tokio::time::timeout(PING_TIMEOUT, ping).await, Ok(Ok(true)))
+    }
+
+    /// Wait until the keeper cluster has elected a leader and all of its
+    /// enabled followers are in sync, and return the leader's id
+    ///
+    /// Once this returns, any running keeper gives an up to date answer about
+    /// the cluster membership.
+    pub async fn wait_for_keeper_cluster(
+        &self,
+        timeout: Duration,
+    ) -> Result<KeeperId> {
+        // Disabled keepers are stopped, so they never catch up
+        let disabled = self
+            .meta
+            .as_ref()
+            .map_or(0, |meta| meta.disabled_keepers.len() as u64);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let leader = self.find_keeper_leader_stats().await?;
+            if let Some((id, stats)) = &leader {
+                if stats.is_synced_ignoring(disabled) {
+                    return Ok(*id);
+                }
+            }
+            if Instant::now() >= deadline {
+                match leader {
+                    Some((id, _)) => bail!(
+                        "Keeper leader {id} still has unsynced followers \
+                         after {timeout:?}"
+                    ),
+                    None => bail!("No keeper leader elected after {timeout:?}"),
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait until a keeper answers `mntr`
+    pub async fn wait_for_keeper_ready(
+        &self,
+        id: KeeperId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let zk = KeeperClient::new(self.keeper_addr(id)?);
+        let deadline = Instant::now() + timeout;
+        loop {
+            match zk.mntr().await {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(anyhow::Error::new(e).context(format!(
+                        "keeper {id} not ready after {timeout:?}"
+                    )));
+                }
+                Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Wait until the clickhouse server answers `/ping`
+    pub async fn wait_for_server(
+        &self,
+        id: ServerId,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.server_is_ready(id).await {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("clickhouse server {id} not ready after {timeout:?}");
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Deploy the cluster and wait until it is ready to serve queries
+    ///
+    /// The cluster is ready once the keepers have elected a leader and every
+    /// clickhouse server answers `/ping`. If that doesn't happen within
+    /// `timeout`, the returned error describes the readiness of every node.
+    pub async fn deploy_and_wait(&self, timeout: Duration) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        self.deploy()?;
+
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let mut res = self.wait_for_keeper_cluster(timeout).await.map(|_| ());
+        if res.is_ok() {
+            self.report_timing("keeper quorum ready", start);
+        }
+        for &id in meta.server_ids.difference(&meta.disabled_servers) {
+            if res.is_err() {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            res = self.wait_for_server(id, remaining).await;
+        }
+        if res.is_ok() {
+            self.report_timing("all clickhouse servers ready", start);
+        }
+        if let Err(e) = res {
+            bail!("{e}\n{}", self.readiness_summary().await?);
+        }
+        Ok(())
+    }
+
+    /// Describe whether each node is running and responding
+    async fn readiness_summary(&self) -> Result<String> {
+        let status = self.status()?;
+        let mut lines = vec![];
+        for (id, node_status) in status.keepers {
+            let state = if node_status == NodeStatus::Stopped {
+                "not running".to_string()
+            } else {
+                let zk = KeeperClient::new(self.keeper_addr(id)?);
+                match zk.mntr().await {
+                    Ok(stats) => {
+                        stats.server_state().unwrap_or("unknown").to_string()
+                    }
+                    Err(_) => "not responding".to_string(),
+                }
+            };
+            lines.push(format!("  keeper {id}: {state}"));
+        }
+        for (id, node_status) in status.servers {
+            let state = if node_status == NodeStatus::Stopped {
+                "not running"
+            } else if self.server_is_ready(id).await {
+                "ready"
+            } else {
+                "not responding"
+            };
+            lines.push(format!("  clickhouse server {id}: {state}"));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Return a client for running SQL against clickhouse server `id`
+    pub fn server_client(&self, id: ServerId) -> ServerClient {
+        ServerClient::new(self.native_addr(id))
+    }
+
+    /// Run `sql` on every clickhouse server of the deployment
+    ///
+    /// A failure on one server doesn't stop `sql` from being run on the
+    /// others. The output or error of each server is returned.
+    pub async fn run_sql_on_all(
+        &self,
+        sql: &str,
+    ) -> Result<BTreeMap<ServerId, Result<String, ServerError>>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut results = BTreeMap::new();
+        for &id in &meta.server_ids {
+            results.insert(id, self.server_client(id).query(sql).await);
+        }
+        Ok(results)
+    }
+
+    /// Run `sql` on a single running clickhouse server, e.g. for `ON CLUSTER`
+    /// DDL, and return which server ran it along with its output
+    pub async fn run_sql_on_one(
+        &self,
+        sql: &str,
+    ) -> Result<(ServerId, String)> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let Some(&id) =
+            meta.server_ids.iter().find(|&&id| self.server_is_running(id))
+        else {
+            bail!("No clickhouse server is running");
+        };
+        let output = self.server_client(id).query(sql).await?;
+        Ok((id, output))
+    }
+
+    /// Wait until every running clickhouse server has reloaded its config and
+    /// sees the cluster topology recorded in the metadata
+    ///
+    /// ClickHouse picks up config changes on its own, but only after a delay.
+    /// This checks `system.clusters` on each server, so that callers can be
+    /// sure a membership change took effect.
+    pub async fn wait_for_cluster_reload(
+        &self,
+        timeout: Duration,
+    ) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        // Servers are identified by their native port, since the address
+        // ClickHouse reports depends on how it resolved the host
+        let expected: BTreeSet<u16> =
+            meta.server_ids.iter().map(|&id| self.native_port(id)).collect();
+        let sql = format!(
+            "SELECT port FROM system.clusters WHERE cluster = '{}' \
+             FORMAT TSV",
+            self.config.cluster_name.replace('\'', "\\'")
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut stale = vec![];
+            for &id in &meta.server_ids {
+                if !self.server_is_running(id) {
+                    continue;
+                }
+                let seen: Option<BTreeSet<u16>> =
+                    match self.server_client(id).query(&sql).await {
+                        Ok(output) => output
+                            .lines()
+                            .map(|l| l.trim().parse().ok())
+                            .collect(),
+                        Err(_) => None,
+                    };
+                if seen.as_ref() != Some(&expected) {
+                    stale.push(id.to_string());
+                }
+            }
+            if stale.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "clickhouse servers {} did not pick up the new cluster \
+                     config after {timeout:?}",
+                    stale.join(", ")
+                );
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Decide whether a membership change can be applied to the live cluster
+    ///
+    /// If nothing is running we can only regenerate configs. If the keeper
+    /// quorum is running, the change can be applied live. Anything in between
+    /// is an error, since a live change would not take effect correctly.
+    fn membership_change_mode(&self) -> Result<MembershipChange> {
+        let status = self.status()?;
+        let counts = status.counts();
+        if !counts.any_running() {
+            return Ok(MembershipChange::ConfigOnly);
+        }
+        if !status.has_keeper_quorum() {
+            bail!(
+                "Keeper quorum is not running ({}/{} keepers up): \
+                 run `deploy` or `teardown` before changing membership",
+                counts.keepers_running,
+                counts.keepers_total
+            );
+        }
+        Ok(MembershipChange::Live)
+    }
+
+    /// Wait until the keeper leader accepts writes
+    ///
+    /// Keepers can be read-only for a while during reconfiguration, and a
+    /// membership change attempted then fails confusingly.
+    async fn wait_for_writable_keeper_leader(&self) -> Result<()> {
+        let deadline = Instant::now() + KEEPER_WRITABLE_TIMEOUT;
+        loop {
+            if let Some(id) = self.find_keeper_leader().await? {
+                let zk = KeeperClient::new(self.keeper_addr(id)?);
+                if let Ok(false) = zk.is_read_only().await {
+                    return Ok(());
+                }
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "No writable keeper leader after {:?}: \
+                     refusing to change membership",
+                    KEEPER_WRITABLE_TIMEOUT
+                );
+            }
+            println!("Waiting for a writable keeper leader");
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Return true if any node of this deployment is currently running
+    pub fn is_deployed(&self) -> bool {
+        self.running_counts().map(|c| c.any_running()).unwrap_or(false)
+    }
+
+    /// Stop all clickhouse servers and keepers
+    ///
+    /// Teardown is best-effort: a node that fails to stop does not prevent the
+    /// others from being stopped. The returned report records what happened to
+    /// each node.
+    pub fn teardown(&self) -> Result<TeardownReport> {
+        let mut report = TeardownReport::default();
+        let Some(meta) = &self.meta else {
+            return Ok(report);
+        };
+        let outcome = |running: bool, stop: &dyn Fn() -> Result<()>| {
+            if !running {
+                return StopOutcome::WasNotRunning;
+            }
+            match stop() {
+                Ok(()) => StopOutcome::Stopped,
+                Err(e) => StopOutcome::Failed(format!("{e:#}")),
+            }
+        };
+        for &id in &meta.keeper_ids {
+            let o =
+                outcome(self.keeper_is_running(id), &|| self.stop_keeper(id));
+            report.keepers.insert(id, o);
+        }
+        for &id in &meta.server_ids {
+            let o =
+                outcome(self.server_is_running(id), &|| self.stop_server(id));
+            report.servers.insert(id, o);
+        }
+        Ok(report)
+    }
+
+    /// Stop all clickhouse servers and keepers and remove their data
+    ///
+    /// The `data` directory and extra disks of each clickhouse server and the
+    /// `coordination` directory of each keeper are deleted, so that a
+    /// subsequent `deploy` starts from a clean slate. Nothing is deleted until
+    /// every node has exited. Configuration files and clickward metadata
+    /// are preserved unless `include_configs` is true, in which case the
+    /// generated XML configs are removed as well.
+    pub fn wipe(&self, include_configs: bool) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let report = self.teardown()?;
+        if let Some((node, err)) = report.failures().next() {
+            bail!("refusing to wipe: failed to stop {node}: {err}");
+        }
+        if self.running_counts()?.any_running() {
+            bail!("refusing to wipe: some nodes are still running");
+        }
+
+        for id in &meta.keeper_ids {
+            let dir = self.config.path.join(format!("keeper-{id}"));
+            println!("Wiping keeper: {dir}");
+            remove_dir_if_exists(&dir.join("coordination"))?;
+            if include_configs {
+                remove_file_if_exists(
+                    &dir.join(keeper_config_filename(meta.settings.format)),
+                )?;
+            }
+        }
+        for id in &meta.server_ids {
+            let dir = self.config.path.join(format!("clickhouse-{id}"));
+            println!("Wiping clickhouse server: {dir}");
+            remove_dir_if_exists(&dir.join("data"))?;
+            // Disk directories are created along with the configs, so leave
+            // them in place but empty
+            for disk in meta.settings.storage.resolve(&dir).disks {
+                remove_dir_if_exists(&disk.path)?;
+                std::fs::create_dir_all(&disk.path)?;
+            }
+            if include_configs {
+                remove_file_if_exists(
+                    &dir.join(clickhouse_config_filename(meta.settings.format)),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a node to clickhouse keeper config at all replicas and start the new
+    /// keeper
+    ///
+    /// If the deployment isn't running, only the configs are regenerated.
+    pub async fn add_keeper(&mut self) -> Result<MembershipChange> {
+        self.add_keeper_impl(None).await
+    }
+
+    /// Like `add_keeper`, but reuse the id of a previously removed keeper
+    /// instead of allocating a new one
+    ///
+    /// Any raft state left behind by the old keeper is deleted first, so the
+    /// new keeper joins the cluster from scratch.
+    pub async fn add_keeper_with_id(
+        &mut self,
+        id: KeeperId,
+    ) -> Result<MembershipChange> {
+        self.add_keeper_impl(Some(id)).await
+    }
+
+    async fn add_keeper_impl(
+        &mut self,
+        id: Option<KeeperId>,
+    ) -> Result<MembershipChange> {
+        self.check_not_recovered()?;
+        let mode = self.membership_change_mode()?;
+        if mode == MembershipChange::Live {
+            self.wait_for_writable_keeper_leader().await?;
+        }
+        let path = &self.config.path;
+        let (old_meta, new_id, meta) = if let Some(meta) = &mut self.meta {
+            let old_meta = meta.clone();
+            let new_id = match id {
+                Some(id) => {
+                    meta.add_keeper_with_id(id)?;
+                    let dir = path.join(format!("keeper-{id}"));
+                    remove_dir_if_exists(&dir.join("coordination"))?;
+                    id
+                }
+                None => meta.add_keeper(),
+            };
+            println!("Updating config to include new keeper: {new_id}");
+            meta.save(path)?;
+            (old_meta, new_id, meta.clone())
+        } else {
+            bail!(MISSING_META);
+        };
+
+        // Until every step has succeeded, undo the addition on failure rather
+        // than leave the metadata ahead of reality
+        let mut spawned = None;
+        if let Err(e) =
+            self.add_keeper_steps(new_id, &meta, mode, &mut spawned).await
+        {
+            let context =
+                match self.rollback_add_keeper(new_id, old_meta, spawned) {
+                    Ok(()) => {
+                        format!(
+                        "Adding keeper {new_id} failed: addition rolled back"
+                    )
+                    }
+                    Err(rollback_err) => format!(
+                        "Adding keeper {new_id} failed, and so did rolling it \
+                     back: {rollback_err:#}"
+                    ),
+                };
+            return Err(e.context(context));
+        }
+
+        Ok(mode)
+    }
+
+    /// Bring up keeper `new_id` and point every other node at `meta`'s
+    /// topology
+    ///
+    /// The keeper process is stored in `spawned` as soon as it is started, so
+    /// that a rollback can stop it even before it has written its pidfile.
+    async fn add_keeper_steps(
+        &self,
+        new_id: KeeperId,
+        meta: &ClickwardMetadata,
+        mode: MembershipChange,
+        spawned: &mut Option<Child>,
+    ) -> Result<()> {
+        // We update the new node and start it before the other nodes. It must be online
+        // for reconfiguration to succeed.
+        self.generate_keeper_config(
+            new_id,
+            meta.keeper_ids.clone(),
+            &meta.settings,
+        )
+        .with_context(|| {
+            format!("Failed to generate config of keeper {new_id}")
+        })?;
+        if mode == MembershipChange::Live {
+            *spawned = Some(self.start_keeper(new_id)?);
+
+            // Peers can't add a keeper that isn't serving yet
+            self.wait_for_keeper_ready(new_id, KEEPER_READY_TIMEOUT).await?;
+        }
+
+        // Generate new configs for all the other keepers
+        // They will automatically reload them.
+        let mut other_keepers = meta.keeper_ids.clone();
+        other_keepers.remove(&new_id);
         for id in other_keepers {
-            self.generate_keeper_config(id, meta.keeper_ids.clone())?;
+            self.generate_keeper_config(
+                id,
+                meta.keeper_ids.clone(),
+                &meta.settings,
+            )
+            .with_context(|| {
+                format!("Failed to regenerate config of keeper {id}")
+            })?;
         }
 
         // Update clickhouse configs so they know about the new keeper node
         self.generate_clickhouse_config(
             meta.keeper_ids.clone(),
             meta.server_ids.clone(),
-        )?;
+            &meta.settings,
+        )
+        .context("Failed to regenerate clickhouse server configs")
+    }
+
+    /// Restore the deployment to `old_meta` after adding keeper `new_id`
+    /// failed part way through
+    fn rollback_add_keeper(
+        &mut self,
+        new_id: KeeperId,
+        old_meta: ClickwardMetadata,
+        spawned: Option<Child>,
+    ) -> Result<()> {
+        // The keeper may never have been started
+        if let Some(mut child) = spawned {
+            // Killing fails only if the keeper already exited, in which case
+            // `wait` reaps it
+            let _ = child.kill();
+            child.wait().with_context(|| {
+                format!("Failed to wait for keeper {new_id} to exit")
+            })?;
+            remove_file_if_exists(&self.keeper_dir(new_id).join("keeper.pid"))?;
+        }
+        if new_id > old_meta.max_keeper_id {
+            remove_dir_if_exists(&self.keeper_dir(new_id))?;
+        }
+        old_meta.save(&self.config.path)?;
+        self.meta = Some(old_meta.clone());
+        self.generate_keeper_configs(&old_meta.keeper_ids, &old_meta.settings)?;
+        self.generate_clickhouse_config(
+            old_meta.keeper_ids.clone(),
+            old_meta.server_ids.clone(),
+            &old_meta.settings,
+        )
+    }
+
+    /// Return the raft priority of every keeper
+    pub fn keeper_priorities(&self) -> Result<BTreeMap<KeeperId, u8>> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        Ok(meta
+            .keeper_ids
+            .iter()
+            .map(|id| {
+                let priority = meta
+                    .settings
+                    .keeper_priorities
+                    .get(id)
+                    .copied()
+                    .unwrap_or(DEFAULT_RAFT_PRIORITY);
+                (*id, priority)
+            })
+            .collect())
+    }
+
+    /// Set the raft priority of a keeper and regenerate all keeper configs
+    ///
+    /// Running keepers reload their configs automatically.
+    pub fn set_keeper_priority(
+        &mut self,
+        id: KeeperId,
+        priority: u8,
+    ) -> Result<()> {
+        self.check_not_recovered()?;
+        let meta = if let Some(meta) = &mut self.meta {
+            meta.check_member(NodeRef::Keeper(id))?;
+            println!("Setting priority of keeper {id} to {priority}");
+            if priority == DEFAULT_RAFT_PRIORITY {
+                meta.settings.keeper_priorities.remove(&id);
+            } else {
+                meta.settings.keeper_priorities.insert(id, priority);
+            }
+            meta.save(&self.config.path)?;
+            meta.clone()
+        } else {
+            bail!(MISSING_META);
+        };
 
+        self.generate_keeper_configs(&meta.keeper_ids, &meta.settings)?;
         Ok(())
     }
 
     /// Add a new clickhouse server replica
-    pub fn add_server(&mut self) -> Result<()> {
+    ///
+    /// If the deployment isn't running, only the configs are regenerated.
+    pub fn add_server(&mut self) -> Result<MembershipChange> {
+        self.add_server_impl(None)
+    }
+
+    /// Like `add_server`, but reuse the id of a previously removed server
+    /// instead of allocating a new one
+    pub fn add_server_with_id(
+        &mut self,
+        id: ServerId,
+    ) -> Result<MembershipChange> {
+        self.add_server_impl(Some(id))
+    }
+
+    fn add_server_impl(
+        &mut self,
+        id: Option<ServerId>,
+    ) -> Result<MembershipChange> {
+        self.check_not_recovered()?;
+        let mode = self.membership_change_mode()?;
         let (new_id, meta) = if let Some(meta) = &mut self.meta {
-            let new_id = meta.add_server();
+            let new_id = match id {
+                Some(id) => {
+                    meta.add_server_with_id(id)?;
+                    id
+                }
+                None => meta.add_server(),
+            };
             println!("Updating config to include new replica: {new_id}");
             meta.save(&self.config.path)?;
             (new_id, meta.clone())
@@ -312,17 +1783,32 @@ impl Deployment {
         };
 
         // Update clickhouse configs so they know about the new replica
-        self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
+        self.generate_clickhouse_config(
+            meta.keeper_ids,
+            meta.server_ids,
+            &meta.settings,
+        )?;
 
         // Start the new replica
-        self.start_server(new_id)?;
+        if mode == MembershipChange::Live {
+            self.start_server(new_id)?;
+        }
 
-        Ok(())
+        Ok(mode)
     }
 
     /// Remove a node from clickhouse keeper config at all replicas and stop the
     /// old replica.
-    pub fn remove_keeper(&mut self, id: KeeperId) -> Result<()> {
+    pub async fn remove_keeper(&mut self, id: KeeperId) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        // Check the id before waiting on the cluster
+        meta.check_member(NodeRef::Keeper(id))?;
+        self.check_not_recovered()?;
+        if self.running_counts()?.keepers_running > 0 {
+            self.wait_for_writable_keeper_leader().await?;
+        }
         println!("Updating config to remove keeper: {id}");
         let meta = if let Some(meta) = &mut self.meta {
             meta.remove_keeper(id)?;
@@ -332,23 +1818,73 @@ impl Deployment {
             bail!(MISSING_META);
         };
 
-        for id in &meta.keeper_ids {
-            self.generate_keeper_config(*id, meta.keeper_ids.clone())?;
+        self.generate_keeper_configs(&meta.keeper_ids, &meta.settings)?;
+        if self.keeper_is_running(id) {
+            self.stop_keeper(id)?;
         }
-        self.stop_keeper(id)?;
 
         // Update clickhouse configs so they know about the removed keeper node
         self.generate_clickhouse_config(
             meta.keeper_ids.clone(),
             meta.server_ids.clone(),
+            &meta.settings,
         )?;
 
         Ok(())
     }
 
+    /// Remove several keepers, one at a time
+    ///
+    /// Every id is checked before anything is removed. The current leader, if
+    /// it is being removed, goes last so the cluster only has to elect a new
+    /// leader once.
+    pub async fn remove_keepers(
+        &mut self,
+        ids: &BTreeSet<KeeperId>,
+    ) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut check = meta.clone();
+        for &id in ids {
+            check.remove_keeper(id)?;
+        }
+        if check.keeper_ids.is_empty() {
+            bail!("Refusing to remove every keeper of the deployment");
+        }
+        let leader = match self.running_counts()?.keepers_running {
+            0 => None,
+            _ => self.find_keeper_leader().await?,
+        };
+        let (last, first): (Vec<_>, Vec<_>) =
+            ids.iter().partition(|&&id| Some(id) == leader);
+        for id in first.into_iter().chain(last) {
+            self.remove_keeper(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove several clickhouse servers, one at a time
+    ///
+    /// Every id is checked before anything is removed.
+    pub fn remove_servers(&mut self, ids: &BTreeSet<ServerId>) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let mut check = meta.clone();
+        for &id in ids {
+            check.remove_server(id)?;
+        }
+        for &id in ids {
+            self.remove_server(id)?;
+        }
+        Ok(())
+    }
+
     /// Remove a node from clickhouse server config at all replicas and stop the
     /// old server.
     pub fn remove_server(&mut self, id: ServerId) -> Result<()> {
+        self.check_not_recovered()?;
         println!("Updating config to remove clickhouse server: {id}");
         let meta = if let Some(meta) = &mut self.meta {
             meta.remove_server(id)?;
@@ -359,50 +1895,170 @@ impl Deployment {
         };
 
         // Update clickhouse configs so they know about the removed keeper node
-        self.generate_clickhouse_config(meta.keeper_ids, meta.server_ids)?;
+        self.generate_clickhouse_config(
+            meta.keeper_ids,
+            meta.server_ids,
+            &meta.settings,
+        )?;
 
         // Stop the clickhouse server
-        self.stop_server(id)?;
+        if self.server_is_running(id) {
+            self.stop_server(id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the directory of a removed node, including its configs, logs
+    /// and data
+    ///
+    /// A removed node's directory is otherwise left behind, in case its data
+    /// is still of interest.
+    ///
+    /// Removing a node waits for it to exit. A node whose pidfile is gone may
+    /// still be running though, e.g. if the pidfile was deleted by hand, so
+    /// where `/proc` is available the node only counts as stopped if no
+    /// process refers to its directory.
+    pub fn purge_node(&self, node: NodeRef) -> Result<()> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let (dir, is_member) = match node {
+            NodeRef::Keeper(id) => {
+                (self.keeper_dir(id), meta.keeper_ids.contains(&id))
+            }
+            NodeRef::Server(id) => {
+                (self.server_dir(id), meta.server_ids.contains(&id))
+            }
+        };
+        if is_member {
+            bail!("{node} is part of the deployment: remove it before purging");
+        }
+        let pidfile_is_running = || match node {
+            NodeRef::Keeper(id) => self.keeper_is_running(id),
+            NodeRef::Server(id) => self.server_is_running(id),
+        };
+        match pids_using_dir(&dir) {
+            Some(pids) if !pids.is_empty() => bail!(
+                "{node} is still running as pids {pids:?}: refusing to purge it"
+            ),
+            None if pidfile_is_running() => {
+                bail!("{node} is still running: refusing to purge it")
+            }
+            _ => {}
+        }
+        println!("Deleting {dir}");
+        remove_dir_if_exists(&dir)
+    }
+
+    /// Stop a keeper without removing it from the cluster
+    ///
+    /// The keeper stays in every config, so the rest of the cluster sees it
+    /// as down rather than reconfiguring. It is skipped by `deploy` until it
+    /// is enabled again.
+    pub fn disable_keeper(&mut self, id: KeeperId) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.set_keeper_disabled(id, true)?;
+        meta.save(&self.config.path)?;
+        if self.keeper_is_running(id) {
+            self.stop_keeper(id)?;
+        }
+        Ok(())
+    }
+
+    /// Clear a keeper's disabled flag and start it if it isn't running
+    pub fn enable_keeper(&mut self, id: KeeperId) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.set_keeper_disabled(id, false)?;
+        meta.save(&self.config.path)?;
+        if !self.keeper_is_running(id) {
+            self.start_keeper(id)?;
+        }
+        Ok(())
+    }
+
+    /// Stop a clickhouse server without removing it from the cluster
+    ///
+    /// It is skipped by `deploy` until it is enabled again.
+    pub fn disable_server(&mut self, id: ServerId) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.set_server_disabled(id, true)?;
+        meta.save(&self.config.path)?;
+        if self.server_is_running(id) {
+            self.stop_server(id)?;
+        }
+        Ok(())
+    }
 
+    /// Clear a server's disabled flag and start it if it isn't running
+    pub fn enable_server(&mut self, id: ServerId) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.set_server_disabled(id, false)?;
+        meta.save(&self.config.path)?;
+        if !self.server_is_running(id) {
+            self.start_server(id)?;
+        }
         Ok(())
     }
 
-    pub fn start_keeper(&self, id: KeeperId) -> Result<()> {
+    /// Start a keeper in the background
+    ///
+    /// Dropping the returned process leaves the keeper running.
+    pub fn start_keeper(&self, id: KeeperId) -> Result<Child> {
         let dir = self.config.path.join(format!("keeper-{id}"));
         println!("Deploying keeper: {dir}");
-        let config = dir.join("keeper-config.xml");
+        let config = dir.join(keeper_config_filename(self.config_format()));
         let pidfile = dir.join("keeper.pid");
-        Command::new("clickhouse")
-            .arg("keeper")
-            .arg("-C")
-            .arg(config)
-            .arg("--pidfile")
-            .arg(pidfile)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .context("Failed to start keeper")?;
-        Ok(())
+        let mut cmd = Command::new("clickhouse");
+        cmd.arg("keeper").arg("-C").arg(config).arg("--pidfile").arg(pidfile);
+        self.spawn(cmd, "keeper")
     }
 
-    pub fn start_server(&self, id: ServerId) -> Result<()> {
+    /// Start a clickhouse server in the background
+    ///
+    /// Dropping the returned process leaves the server running.
+    pub fn start_server(&self, id: ServerId) -> Result<Child> {
         let dir = self.config.path.join(format!("clickhouse-{id}"));
         println!("Deploying clickhouse server: {dir}");
-        let config = dir.join("clickhouse-config.xml");
+        let config = dir.join(clickhouse_config_filename(self.config_format()));
         let pidfile = dir.join("clickhouse.pid");
-        Command::new("clickhouse")
-            .arg("server")
+        let mut cmd = Command::new("clickhouse");
+        cmd.arg("server")
             .arg("-C")
             .arg(config)
             .arg("--pidfile")
             .arg(pidfile)
-            .stdin(Stdio::null())
+            // Put the server and the child its watchdog forks into their own
+            // process group, so they can be stopped together
+            .process_group(0);
+        self.spawn(cmd, "clickhouse server")
+    }
+
+    /// Spawn a node in the background, detached from our stdio
+    ///
+    /// The command line is logged at debug level, and is part of the error if
+    /// spawning fails.
+    fn spawn(&self, mut cmd: Command, what: &str) -> Result<Child> {
+        let command_line = command_line(&cmd);
+        log::debug!("Spawning {what}: {command_line}");
+        if self.print_commands {
+            println!("  {command_line}");
+        }
+        cmd.stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
-            .context("Failed to start clickhouse server")?;
-        Ok(())
+            .with_context(|| {
+                format!("Failed to start {what}: `{command_line}`")
+            })
     }
 
     pub fn stop_keeper(&self, id: KeeperId) -> Result<()> {
@@ -411,14 +2067,19 @@ impl Deployment {
         let pid = std::fs::read_to_string(&pidfile)?;
         let pid = pid.trim_end();
         println!("Stopping keeper: {dir} at pid {pid}");
-        Command::new("kill")
+        let status = Command::new("kill")
             .arg("-9")
             .arg(pid)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
-            .spawn()
+            .status()
             .context("Failed to kill keeper")?;
+        if !status.success() {
+            bail!("Failed to kill keeper {id}: {status}");
+        }
+        wait_for_exit(&[pid.to_string()])
+            .with_context(|| format!("Keeper {id} did not exit"))?;
         std::fs::remove_file(&pidfile)?;
         Ok(())
     }
@@ -430,44 +2091,92 @@ impl Deployment {
         let pid = std::fs::read_to_string(&pidfile)?;
         let pid = pid.trim_end();
 
-        // Retrieve the child process id
-        let output = Command::new("pgrep")
-            .arg("-P")
-            .arg(pid)
-            .output()
-            .context("failed to retreive child process for pid {pid}")?;
-        let child_pid = String::from_utf8(output.stdout)
-            .context("failed to parse child pid for pid {pid}")?;
-        let child_pid = child_pid.trim_end();
-
-        println!("Stopping clickhouse server {name}: pid - {pid}, child pid - {child_pid}");
-
-        // Kill the parent
-        Command::new("kill")
-            .arg("-9")
-            .arg(pid)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .spawn()
-            .context("Failed to kill clickhouse server")?;
-
-        // Kill the child
-        Command::new("kill")
+        // The pidfile holds the pid of the clickhouse watchdog, which forks
+        // the actual server. Where we can see the process tree, kill both
+        // directly. Otherwise kill the process group the server was started
+        // in.
+        let (targets, pids) = match child_pids(pid) {
+            Some(children) => {
+                println!(
+                    "Stopping clickhouse server {name}: pid - {pid}, \
+                     child pids - {children:?}"
+                );
+                let pids: Vec<_> =
+                    std::iter::once(pid.to_string()).chain(children).collect();
+                (pids.clone(), pids)
+            }
+            None => {
+                println!(
+                    "Stopping clickhouse server {name}: process group {pid}"
+                );
+                (vec![format!("-{pid}")], vec![pid.to_string()])
+            }
+        };
+        let status = Command::new("kill")
             .arg("-9")
-            .arg(child_pid)
+            .arg("--")
+            .args(&targets)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
-            .spawn()
+            .status()
             .context("Failed to kill clickhouse server")?;
+        if !status.success() {
+            bail!("Failed to kill clickhouse server {name}: {status}");
+        }
+        wait_for_exit(&pids).with_context(|| {
+            format!("Clickhouse server {name} did not exit")
+        })?;
         std::fs::remove_file(&pidfile)?;
 
         Ok(())
     }
 
     /// Deploy our clickhouse replicas and keeper cluster
+    ///
+    /// Exactly the nodes in the metadata are started, so the directories of
+    /// removed nodes are left alone. Disabled nodes are skipped.
     pub fn deploy(&self) -> Result<()> {
+        let start = Instant::now();
+        let spawned = match &self.meta {
+            Some(meta) => {
+                let keepers: Vec<_> = meta
+                    .keeper_ids
+                    .difference(&meta.disabled_keepers)
+                    .collect();
+                let servers: Vec<_> = meta
+                    .server_ids
+                    .difference(&meta.disabled_servers)
+                    .collect();
+                for &id in &keepers {
+                    self.start_keeper(*id)?;
+                }
+                for &id in &servers {
+                    self.start_server(*id)?;
+                }
+                keepers.len() + servers.len()
+            }
+            None => {
+                println!(
+                    "Warning: no metadata found in {}: starting every node \
+                     directory",
+                    self.config.path
+                );
+                self.deploy_node_dirs()?
+            }
+        };
+        self.report_timing(&format!("spawned {spawned} processes"), start);
+        Ok(())
+    }
+
+    /// Start a node for every node directory, and return how many were
+    /// started
+    ///
+    /// This is only for deployments without metadata, which can't tell
+    /// removed nodes from current ones.
+    fn deploy_node_dirs(&self) -> Result<usize> {
+        let mut spawned = 0;
+        let format = self.config_format();
         let dirs: Vec<_> = self.config.path.read_dir_utf8()?.collect();
 
         // Find all keeper replicas them
@@ -482,19 +2191,16 @@ impl Deployment {
         // Start all keepers
         for dir in keeper_dirs {
             println!("Deploying keeper: {dir}");
-            let config = dir.join("keeper-config.xml");
+            let config = dir.join(keeper_config_filename(format));
             let pidfile = dir.join("keeper.pid");
-            Command::new("clickhouse")
-                .arg("keeper")
+            let mut cmd = Command::new("clickhouse");
+            cmd.arg("keeper")
                 .arg("-C")
                 .arg(config)
                 .arg("--pidfile")
-                .arg(pidfile)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .context("Failed to start keeper")?;
+                .arg(pidfile);
+            self.spawn(cmd, "keeper")?;
+            spawned += 1;
         }
 
         // Find all clickhouse replicas
@@ -510,22 +2216,20 @@ impl Deployment {
         // Start all clickhouse servers
         for dir in clickhouse_dirs {
             println!("Deploying clickhouse server: {dir}");
-            let config = dir.join("clickhouse-config.xml");
+            let config = dir.join(clickhouse_config_filename(format));
             let pidfile = dir.join("clickhouse.pid");
-            Command::new("clickhouse")
-                .arg("server")
+            let mut cmd = Command::new("clickhouse");
+            cmd.arg("server")
                 .arg("-C")
                 .arg(config)
                 .arg("--pidfile")
                 .arg(pidfile)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .spawn()
-                .context("Failed to start clickhouse server")?;
+                .process_group(0);
+            self.spawn(cmd, "clickhouse server")?;
+            spawned += 1;
         }
 
-        Ok(())
+        Ok(spawned)
     }
 
     /// Generate configuration for our clusters
@@ -534,97 +2238,673 @@ impl Deployment {
         num_keepers: u64,
         num_replicas: u64,
     ) -> Result<()> {
-        std::fs::create_dir_all(&self.config.path).unwrap();
+        self.generate_config_with_settings(
+            num_keepers,
+            num_replicas,
+            ConfigSettings::default(),
+        )
+    }
+
+    /// Generate configuration for our clusters using `settings`
+    ///
+    /// The deployment is checked with `DeploymentSpec::validate_layout` before
+    /// anything is written, and must not overlap the ports of any other deployment under
+    /// the same path. Ports and the cluster name set in `settings` take
+    /// precedence over those of the `DeploymentConfig`.
+    pub fn generate_config_with_settings(
+        &mut self,
+        num_keepers: u64,
+        num_replicas: u64,
+        mut settings: ConfigSettings,
+    ) -> Result<()> {
+        let explicit_ports = settings.base_ports.is_some();
+        self.config.apply_settings(&settings);
+        settings.base_ports = Some(self.config.base_ports.clone());
+        settings.cluster_name = Some(self.config.cluster_name.clone());
+        let mut spec = DeploymentSpec {
+            settings,
+            ..DeploymentSpec::new(num_keepers, num_replicas)
+        };
+        spec.validate_layout()?;
+        let siblings = self.sibling_deployments()?;
+        if self.config.name.is_some() {
+            self.isolate_named(&mut spec, explicit_ports, &siblings)?;
+            self.config.apply_settings(&spec.settings);
+        }
+        let settings = spec.settings;
 
         let keeper_ids: BTreeSet<KeeperId> =
             (1..=num_keepers).map(KeeperId).collect();
         let replica_ids: BTreeSet<ServerId> =
             (1..=num_replicas).map(ServerId).collect();
+        let meta = ClickwardMetadata::with_settings(
+            keeper_ids.clone(),
+            replica_ids.clone(),
+            settings.clone(),
+        );
+        let candidate =
+            Deployment::from_parts(self.config.clone(), Some(meta.clone()));
+        let mut all: Vec<_> = siblings.iter().collect();
+        all.push(&candidate);
+        check_no_overlap(&all)?;
+        std::fs::create_dir_all(&self.config.path).unwrap();
 
+        let start = Instant::now();
         self.generate_clickhouse_config(
             keeper_ids.clone(),
             replica_ids.clone(),
+            &settings,
         )?;
-        for id in &keeper_ids {
-            self.generate_keeper_config(*id, keeper_ids.clone())?;
-        }
+        self.generate_keeper_configs(&keeper_ids, &settings)?;
+        self.report_timing(
+            &format!("generated {} configs", num_keepers + num_replicas),
+            start,
+        );
 
-        let meta = ClickwardMetadata::new(keeper_ids, replica_ids);
         meta.save(&self.config.path)?;
         self.meta = Some(meta);
 
         Ok(())
     }
-    fn generate_clickhouse_config(
+
+    /// Give a new named deployment its own secret and, unless `spec` was
+    /// given ports explicitly, the lowest ports clear of `siblings`
+    fn isolate_named(
         &self,
-        keeper_ids: BTreeSet<KeeperId>,
-        replica_ids: BTreeSet<ServerId>,
+        spec: &mut DeploymentSpec,
+        explicit_ports: bool,
+        siblings: &[Deployment],
     ) -> Result<()> {
-        let cluster = self.config.cluster_name.clone();
+        if spec.settings.secret == DEFAULT_CLUSTER_SECRET {
+            spec.settings.secret = random_secret();
+        }
+        if explicit_ports {
+            return Ok(());
+        }
+        let keeper_ids: BTreeSet<KeeperId> =
+            (1..=spec.num_keepers).map(KeeperId).collect();
+        let replica_ids: BTreeSet<ServerId> =
+            (1..=spec.num_replicas).map(ServerId).collect();
+        let base = self.config.base_ports.clone();
+        let mut offset = 0;
+        while let Some(ports) = base.shifted(offset) {
+            let mut candidate = spec.clone();
+            candidate.settings.base_ports = Some(ports);
+            // Stop once the ports run out of range
+            if candidate.validate_layout().is_err() {
+                break;
+            }
+            let meta = ClickwardMetadata::with_settings(
+                keeper_ids.clone(),
+                replica_ids.clone(),
+                candidate.settings.clone(),
+            );
+            let mut config = self.config.clone();
+            config.apply_settings(&meta.settings);
+            let d = Deployment::from_parts(config, Some(meta));
+            let mut all: Vec<_> = siblings.iter().collect();
+            all.push(&d);
+            if check_no_overlap(&all).is_ok() {
+                *spec = candidate;
+                return Ok(());
+            }
+            let Some(next) = offset.checked_add(NAMED_PORT_STEP) else {
+                break;
+            };
+            offset = next;
+        }
+        bail!("No free ports left for deployment {}", self.config.path)
+    }
+
+    /// Load the other deployments under the same path as this one
+    fn sibling_deployments(&self) -> Result<Vec<Deployment>> {
+        let dir = match &self.config.name {
+            Some(_) => self
+                .config
+                .path
+                .parent()
+                .expect("named deployments are below DEPLOYMENT_DIR"),
+            None => self.config.path.as_path(),
+        };
+        let mut paths = vec![dir.to_path_buf()];
+        if dir.is_dir() {
+            for entry in dir.read_dir_utf8()? {
+                paths.push(entry?.into_path());
+            }
+        }
+        let mut siblings = vec![];
+        for path in paths {
+            if path == self.config.path
+                || !path.join(CLICKWARD_META_FILENAME).is_file()
+            {
+                continue;
+            }
+            let config = DeploymentConfig {
+                path,
+                base_ports: DEFAULT_BASE_PORTS,
+                cluster_name: self.config.cluster_name.clone(),
+                name: None,
+            };
+            siblings.push(Deployment::try_new(config)?);
+        }
+        Ok(siblings)
+    }
+    /// Generate configuration for the deployment described by `spec`
+    ///
+    /// The spec is validated before anything is written.
+    pub fn generate_config_from_spec(
+        &mut self,
+        spec: &DeploymentSpec,
+    ) -> Result<()> {
+        spec.validate()?;
+        let mut spec = spec.clone();
+        spec.normalize();
+        self.generate_config_with_settings(
+            spec.num_keepers,
+            spec.num_replicas,
+            spec.settings.clone(),
+        )
+    }
+
+    /// Generate a new deployment in `new_path` with the same nodes and
+    /// settings as this one, but listening on `base_ports`
+    ///
+    /// `new_path` is the directory the clone's nodes and metadata go in. Only
+    /// configs are generated: no data is copied, so the clone starts out as an
+    /// empty cluster. The ports of the two deployments must not overlap.
+    ///
+    /// The clone keeps this deployment's cluster name and secret unless
+    /// others are given, in which case its servers can't query this
+    /// deployment's servers or be queried by them.
+    pub fn clone_to(
+        &self,
+        new_path: &Utf8Path,
+        base_ports: BasePorts,
+        cluster_name: Option<String>,
+        secret: Option<String>,
+    ) -> Result<Deployment> {
+        self.check_not_recovered()?;
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        if new_path.join(CLICKWARD_META_FILENAME).exists() {
+            bail!("{new_path} already holds a deployment");
+        }
+        if let Some(cluster_name) = &cluster_name {
+            validate_element_name("cluster", cluster_name)
+                .map_err(anyhow::Error::msg)?;
+        }
+        if secret.as_ref().is_some_and(|secret| secret.is_empty()) {
+            bail!("The cluster secret may not be empty");
+        }
+        let config = DeploymentConfig {
+            path: new_path.to_path_buf(),
+            base_ports,
+            cluster_name: cluster_name
+                .unwrap_or_else(|| self.config.cluster_name.clone()),
+            name: None,
+        };
+        let mut meta = meta.clone();
+        meta.settings.base_ports = Some(config.base_ports.clone());
+        meta.settings.cluster_name = Some(config.cluster_name.clone());
+        if let Some(secret) = secret {
+            meta.settings.secret = secret;
+        }
+        let mut clone = Deployment::from_parts(config, Some(meta.clone()));
+        clone.print_commands = self.print_commands;
+        clone.timings = self.timings;
+        check_no_overlap(&[self, &clone])?;
+
+        std::fs::create_dir_all(new_path)?;
+        clone.regenerate_all()?;
+        meta.save(new_path)?;
+        Ok(clone)
+    }
 
-        let servers: Vec<_> = replica_ids
+    /// Rewrite the config files of every node from the existing metadata
+    ///
+    /// Node ids, settings and the `data` and `coordination` directories are
+    /// left as they are, so this is safe to run after upgrading clickward.
+    /// Running nodes reload their rewritten configs.
+    pub fn regenerate_all(&self) -> Result<()> {
+        self.check_not_recovered()?;
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        let start = Instant::now();
+        self.generate_clickhouse_config(
+            meta.keeper_ids.clone(),
+            meta.server_ids.clone(),
+            &meta.settings,
+        )?;
+        self.generate_keeper_configs(&meta.keeper_ids, &meta.settings)?;
+        self.report_timing(
+            &format!(
+                "regenerated {} configs",
+                meta.keeper_ids.len() + meta.server_ids.len()
+            ),
+            start,
+        );
+        Ok(())
+    }
+
+    /// Fail if the metadata was recovered and hasn't been accepted yet
+    fn check_not_recovered(&self) -> Result<()> {
+        if self.meta.as_ref().is_some_and(|meta| meta.recovered) {
+            bail!(ClickwardError::RecoveredMetadata);
+        }
+        Ok(())
+    }
+
+    /// Allow configs to be regenerated from recovered metadata, once it has
+    /// been checked and any settings that couldn't be recovered restored
+    pub fn accept_recovered_metadata(&mut self) -> Result<()> {
+        let Some(meta) = &mut self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.recovered = false;
+        meta.save(&self.config.path)?;
+        Ok(())
+    }
+
+    /// Reconstruct the metadata of the deployment in `path` from the configs
+    /// of its nodes
+    ///
+    /// This is for recovering a deployment whose metadata file was lost. Node
+    /// ids, the config format, the cluster name, the secret, the base ports,
+    /// the shard topology and the four letter word allow list are read back
+    /// from the configs. All other settings take their default values, so
+    /// the metadata is marked as recovered.
+    ///
+    /// The directories of removed nodes are left behind with the configs they
+    /// had when they were removed. Current members are told apart from them
+    /// because their configs all list exactly the current members: in the
+    /// raft configuration for keepers, and in `remote_servers` for servers.
+    /// Every other node is recovered as removed.
+    ///
+    /// The shard count is the smallest one that explains the `{shard}` and
+    /// `{replica}` macros of every server, removed ones included. Recovery
+    /// fails if no `ShardTopology` does.
+    pub fn recover_metadata(path: &Utf8Path) -> Result<ClickwardMetadata> {
+        let mut keepers = BTreeMap::new();
+        let mut servers = BTreeMap::new();
+        for entry in path.read_dir_utf8()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if let Some(id) = name.strip_prefix("keeper-") {
+                let id: KeeperId = id
+                    .parse()
+                    .with_context(|| format!("Bad keeper directory: {name}"))?;
+                let config = read_node_config(entry.path(), "keeper-config")?;
+                keepers.insert(id, config);
+            } else if let Some(id) = name.strip_prefix("clickhouse-") {
+                let id: ServerId = id.parse().with_context(|| {
+                    format!("Bad clickhouse server directory: {name}")
+                })?;
+                let config =
+                    read_node_config(entry.path(), "clickhouse-config")?;
+                servers.insert(id, config);
+            }
+        }
+        let (Some((&keeper, keeper_config)), Some((&server, server_config))) =
+            (keepers.first_key_value(), servers.first_key_value())
+        else {
+            bail!("No keeper or clickhouse server configs found in {path}");
+        };
+
+        let format = keeper_config.0;
+        if keepers.values().chain(servers.values()).any(|c| c.0 != format) {
+            bail!("Node configs in {path} are not all in the same format");
+        }
+        for (id, config) in &keepers {
+            let config_id: KeeperId =
+                config.parse(&["keeper_server", "server_id"])?;
+            if config_id != *id {
+                bail!("keeper-{id} has the config of keeper {config_id}");
+            }
+        }
+        // The `{replica}` macro isn't the server id under every replica
+        // macro scheme, so servers are identified by their data path instead
+        for (id, config) in &servers {
+            let data_path = Utf8PathBuf::from(config.value(&["path"])?);
+            if !data_path.ends_with(format!("clickhouse-{id}/data")) {
+                bail!("clickhouse-{id} has the config of another server");
+            }
+        }
+        let cluster_name = server_config.value(&["macros", "cluster"])?;
+
+        let base = |port: u16, id: u64| -> Result<u16> {
+            u16::try_from(id)
+                .ok()
+                .and_then(|id| port.checked_sub(id))
+                .context("Port is lower than the node id")
+        };
+        let raft_servers = |config: &NodeConfig| -> Result<Vec<(u64, u16)>> {
+            let servers = config.node(&[
+                "keeper_server",
+                "raft_configuration",
+                "server",
+            ])?;
+            servers
+                .items()
+                .iter()
+                .map(|server| {
+                    Ok((
+                        parse_config_value(server, "id")?,
+                        parse_config_value(server, "port")?,
+                    ))
+                })
+                .collect()
+        };
+        let Some(&(raft_id, raft_port)) = raft_servers(keeper_config)?.first()
+        else {
+            bail!("keeper-{keeper} has an empty raft configuration");
+        };
+        let base_ports = BasePorts {
+            keeper: base(
+                keeper_config.parse(&["keeper_server", "tcp_port"])?,
+                keeper.0,
+            )?,
+            raft: base(raft_port, raft_id)?,
+            clickhouse_tcp: base(
+                server_config.parse(&["tcp_port"])?,
+                server.0,
+            )?,
+            clickhouse_http: base(
+                server_config.parse(&["http_port"])?,
+                server.0,
+            )?,
+            clickhouse_interserver_http: base(
+                server_config.parse(&["interserver_http_port"])?,
+                server.0,
+            )?,
+            prometheus: DEFAULT_BASE_PORTS.prometheus,
+        };
+
+        let listed_keepers = keepers
             .iter()
-            .map(|&id| ServerConfig {
-                host: "::1".to_string(),
-                port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
+            .map(|(&id, config)| {
+                let ids = raft_servers(config)?
+                    .into_iter()
+                    .map(|(id, _)| KeeperId(id))
+                    .collect();
+                Ok((id, ids))
             })
-            .collect();
-        let remote_servers = RemoteServers {
-            cluster: cluster.clone(),
-            secret: "some-unique-value".to_string(),
-            replicas: servers,
+            .collect::<Result<_>>()?;
+        let keeper_ids = current_members(&listed_keepers, "keeper")?;
+
+        let remote_servers =
+            |config: &NodeConfig| -> Result<Vec<RecoveredShard>> {
+                let cluster =
+                    config.node(&["remote_servers", &cluster_name])?;
+                let shards =
+                    cluster.get("shard").map_or(&[][..], |s| s.items());
+                shards
+                    .iter()
+                    .map(|shard| {
+                        let replicas =
+                            shard.get("replica").map_or(&[][..], |r| r.items());
+                        let ids = replicas
+                            .iter()
+                            .map(|replica| {
+                                let port: u16 =
+                                    parse_config_value(replica, "port")?;
+                                let id = port
+                                    .checked_sub(base_ports.clickhouse_tcp)
+                                    .context(
+                                        "Replica port below the base port",
+                                    )?;
+                                Ok(ServerId(id.into()))
+                            })
+                            .collect::<Result<_>>()?;
+                        let weight = shard
+                            .get("weight")
+                            .map(|_| parse_config_value(shard, "weight"))
+                            .transpose()?;
+                        Ok(RecoveredShard { weight, ids })
+                    })
+                    .collect()
+            };
+        let mut listed_servers = BTreeMap::new();
+        let mut shard_lists = BTreeMap::new();
+        for (&id, config) in &servers {
+            let shards = remote_servers(config)?;
+            let ids = shards.iter().flat_map(|s| s.ids.clone()).collect();
+            listed_servers.insert(id, ids);
+            shard_lists.insert(id, shards);
+        }
+        let server_ids = current_members(&listed_servers, "clickhouse server")?;
+        let member = server_ids.first().expect("members are never empty");
+        let member_config = &servers[member];
+        let shards = recover_shard_topology(&servers, &shard_lists[member])?;
+        let secret = member_config.value(&[
+            "remote_servers",
+            &cluster_name,
+            "secret",
+        ])?;
+
+        // The allow list is omitted from the config if empty
+        let four_letter_words = keeper_config
+            .value(&["keeper_server", "four_letter_word_allow_list"])
+            .map(|words| words.split(',').map(String::from).collect())
+            .unwrap_or_default();
+
+        let settings = ConfigSettings {
+            format,
+            base_ports: Some(base_ports),
+            cluster_name: Some(cluster_name),
+            secret,
+            shards,
+            four_letter_word_allow_list: four_letter_words,
+            ..Default::default()
         };
+        let mut meta =
+            ClickwardMetadata::with_settings(keeper_ids, server_ids, settings);
+        // Removed nodes' ids must never be handed out again
+        meta.max_keeper_id = *keepers.keys().last().expect("checked above");
+        meta.max_server_id = *servers.keys().last().expect("checked above");
+        meta.recovered = true;
+        Ok(meta)
+    }
+
+    /// Return the config of clickhouse server `id` as it is generated from
+    /// the deployment metadata
+    pub fn server_config(&self, id: ServerId) -> Result<ReplicaConfig> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.check_member(NodeRef::Server(id))?;
+        Ok(self.build_clickhouse_config(
+            id,
+            self.build_remote_servers(&meta.server_ids, &meta.settings),
+            self.build_keepers_for_replica(&meta.keeper_ids, &meta.settings),
+            &meta.settings,
+        ))
+    }
+
+    /// Return the config of keeper `id` as it is generated from the
+    /// deployment metadata
+    pub fn keeper_config(&self, id: KeeperId) -> Result<KeeperConfig> {
+        let Some(meta) = &self.meta else {
+            bail!(MISSING_META);
+        };
+        meta.check_member(NodeRef::Keeper(id))?;
+        Ok(self.build_keeper_config(id, &meta.keeper_ids, &meta.settings))
+    }
+
+    fn server_dir(&self, id: ServerId) -> Utf8PathBuf {
+        self.config.path.join(format!("clickhouse-{id}"))
+    }
+
+    fn keeper_dir(&self, id: KeeperId) -> Utf8PathBuf {
+        self.config.path.join(format!("keeper-{id}"))
+    }
+
+    /// Write the config of every server in `replica_ids`
+    fn generate_clickhouse_config(
+        &self,
+        keeper_ids: BTreeSet<KeeperId>,
+        replica_ids: BTreeSet<ServerId>,
+        settings: &ConfigSettings,
+    ) -> Result<()> {
+        // Every server shares these, so only build them once
+        let remote_servers = self.build_remote_servers(&replica_ids, settings);
+        let keepers = self.build_keepers_for_replica(&keeper_ids, settings);
+        let ids: Vec<_> = replica_ids.into_iter().collect();
+        for_each_parallel(&ids, |&id| {
+            self.write_clickhouse_config(
+                id,
+                &remote_servers,
+                &keepers,
+                settings,
+            )
+        })
+    }
+
+    /// Write the config of server `id`, along with the directories it needs
+    fn write_clickhouse_config(
+        &self,
+        id: ServerId,
+        remote_servers: &RemoteServers,
+        keepers: &KeeperConfigsForReplica,
+        settings: &ConfigSettings,
+    ) -> Result<()> {
+        let config = self.build_clickhouse_config(
+            id,
+            remote_servers.clone(),
+            keepers.clone(),
+            settings,
+        );
+        let dir = self.server_dir(id);
+        std::fs::create_dir_all(dir.join("logs"))?;
+        for disk in &config.storage.disks {
+            std::fs::create_dir_all(&disk.path)?;
+        }
+        let mut f = File::create(
+            dir.join(clickhouse_config_filename(settings.format)),
+        )?;
+        f.write_all(config.to_config(settings.format).as_bytes())?;
+        f.flush()?;
+        Ok(())
+    }
+
+    /// The cluster definition shared by every server in `replica_ids`
+    fn build_remote_servers(
+        &self,
+        replica_ids: &BTreeSet<ServerId>,
+        settings: &ConfigSettings,
+    ) -> RemoteServers {
+        // Shards without any servers are left out, since ClickHouse rejects
+        // empty shards
+        let mut shards: BTreeMap<u64, ShardConfig> = BTreeMap::new();
+        for &id in replica_ids {
+            let shard = settings.shards.shard_of(id);
+            shards
+                .entry(shard)
+                .or_insert_with(|| ShardConfig {
+                    weight: settings.shards.weights.get(&shard).copied(),
+                    replicas: vec![],
+                })
+                .replicas
+                .push(ServerConfig {
+                    host: node_ip(settings, NodeRef::Server(id)).to_string(),
+                    port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
+                });
+        }
+        RemoteServers {
+            cluster: self.config.cluster_name.clone(),
+            secret: settings.secret.clone(),
+            shards: shards.into_values().collect(),
+        }
+    }
 
-        let keepers = KeeperConfigsForReplica {
+    /// The keepers every server connects to
+    fn build_keepers_for_replica(
+        &self,
+        keeper_ids: &BTreeSet<KeeperId>,
+        settings: &ConfigSettings,
+    ) -> KeeperConfigsForReplica {
+        KeeperConfigsForReplica {
             nodes: keeper_ids
                 .iter()
                 .map(|&id| ServerConfig {
-                    host: "[::1]".to_string(),
+                    host: node_ip(settings, NodeRef::Keeper(id)).to_string(),
                     port: self.config.base_ports.keeper + id.0 as u16,
                 })
                 .collect(),
-        };
+        }
+    }
 
-        for id in replica_ids {
-            let dir: Utf8PathBuf =
-                [self.config.path.as_str(), &format!("clickhouse-{id}")]
-                    .iter()
-                    .collect();
-            let logs: Utf8PathBuf = dir.join("logs");
-            std::fs::create_dir_all(&logs)?;
-            let log = logs.join("clickhouse.log");
-            let errorlog = logs.join("clickhouse.err.log");
-            let data_path = dir.join("data");
-            let config = ReplicaConfig {
-                logger: LogConfig {
-                    level: LogLevel::Trace,
-                    log,
-                    errorlog,
-                    size: "100M".to_string(),
-                    count: 1,
-                },
-                macros: Macros {
-                    shard: 1,
-                    replica: id,
-                    cluster: cluster.clone(),
-                },
-                listen_host: "::1".to_string(),
-                http_port: self.config.base_ports.clickhouse_http + id.0 as u16,
-                tcp_port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
-                interserver_http_port: self
-                    .config
-                    .base_ports
-                    .clickhouse_interserver_http
-                    + id.0 as u16,
-                remote_servers: remote_servers.clone(),
-                keepers: keepers.clone(),
-                data_path,
-            };
-            let mut f = File::create(dir.join("clickhouse-config.xml"))?;
-            f.write_all(config.to_xml().as_bytes())?;
-            f.flush()?;
+    /// Build the config of server `id` in a cluster described by
+    /// `remote_servers` and `keepers`
+    fn build_clickhouse_config(
+        &self,
+        id: ServerId,
+        remote_servers: RemoteServers,
+        keepers: KeeperConfigsForReplica,
+        settings: &ConfigSettings,
+    ) -> ReplicaConfig {
+        let cluster = self.config.cluster_name.clone();
+        let dir = self.server_dir(id);
+        let logs: Utf8PathBuf = dir.join("logs");
+        let log = logs.join("clickhouse.log");
+        let errorlog = logs.join("clickhouse.err.log");
+        let data_path = dir.join("data");
+        let ip = node_ip(settings, NodeRef::Server(id)).to_string();
+        let storage = settings.storage.resolve(&dir);
+        ReplicaConfig {
+            server_id: id,
+            logger: LogConfig {
+                level: settings.log_level.clone(),
+                log,
+                errorlog,
+                size: "100M".to_string(),
+                count: 1,
+            },
+            macros: Macros {
+                shard: settings.shards.shard_of(id),
+                replica: settings.shards.replica_macro(id),
+                cluster,
+            },
+            listen_hosts: listen_hosts(settings, NodeRef::Server(id)),
+            listen_try: settings.listen_try,
+            interserver_http_host: ip,
+            http_port: self.config.base_ports.clickhouse_http + id.0 as u16,
+            tcp_port: self.config.base_ports.clickhouse_tcp + id.0 as u16,
+            interserver_http_port: self
+                .config
+                .base_ports
+                .clickhouse_interserver_http
+                + id.0 as u16,
+            remote_servers,
+            keepers,
+            data_path,
+            async_insert: settings.async_insert.clone(),
+            prometheus: settings.prometheus_endpoint.as_ref().map(|endpoint| {
+                PrometheusConfig {
+                    port: self.prometheus_port(id),
+                    endpoint: endpoint.clone(),
+                }
+            }),
+            keep_alive_timeout: settings.keep_alive_timeout,
+            http_handlers: settings.http_handlers.clone(),
+            storage,
+            resource_limits: settings.resource_limits.clone(),
+            runtime_tuning: settings.runtime_tuning.clone(),
+            omit_span_log: settings.omit_span_log,
         }
-        Ok(())
+    }
+
+    /// Write the config of every keeper in `keeper_ids`
+    fn generate_keeper_configs(
+        &self,
+        keeper_ids: &BTreeSet<KeeperId>,
+        settings: &ConfigSettings,
+    ) -> Result<()> {
+        let ids: Vec<_> = keeper_ids.iter().copied().collect();
+        for_each_parallel(&ids, |&id| {
+            self.generate_keeper_config(id, keeper_ids.clone(), settings)
+        })
     }
 
     /// Generate a config for `this_keeper` consisting of the replicas in `keeper_ids`
@@ -632,32 +2912,54 @@ impl Deployment {
         &self,
         this_keeper: KeeperId,
         keeper_ids: BTreeSet<KeeperId>,
+        settings: &ConfigSettings,
     ) -> Result<()> {
+        let config =
+            self.build_keeper_config(this_keeper, &keeper_ids, settings);
+        let dir = self.keeper_dir(this_keeper);
+        std::fs::create_dir_all(dir.join("logs"))?;
+        let mut f =
+            File::create(dir.join(keeper_config_filename(settings.format)))?;
+        f.write_all(config.to_config(settings.format).as_bytes())?;
+        f.flush()?;
+
+        Ok(())
+    }
+
+    /// Build the config of `this_keeper` in a raft cluster of `keeper_ids`
+    fn build_keeper_config(
+        &self,
+        this_keeper: KeeperId,
+        keeper_ids: &BTreeSet<KeeperId>,
+        settings: &ConfigSettings,
+    ) -> KeeperConfig {
         let raft_servers: Vec<_> = keeper_ids
             .iter()
             .map(|id| RaftServerConfig {
                 id: *id,
-                hostname: "::1".to_string(),
+                hostname: node_ip(settings, NodeRef::Keeper(*id)).to_string(),
                 port: self.config.base_ports.raft + id.0 as u16,
+                priority: settings
+                    .keeper_priorities
+                    .get(id)
+                    .copied()
+                    .unwrap_or(DEFAULT_RAFT_PRIORITY),
             })
             .collect();
-        let dir: Utf8PathBuf =
-            [self.config.path.as_str(), &format!("keeper-{this_keeper}")]
-                .iter()
-                .collect();
+        let dir = self.keeper_dir(this_keeper);
         let logs: Utf8PathBuf = dir.join("logs");
-        std::fs::create_dir_all(&logs)?;
         let log = logs.join("clickhouse-keeper.log");
         let errorlog = logs.join("clickhouse-keeper.err.log");
-        let config = KeeperConfig {
+        KeeperConfig {
             logger: LogConfig {
-                level: LogLevel::Trace,
+                level: settings.log_level.clone(),
                 log,
                 errorlog,
                 size: "100M".to_string(),
                 count: 1,
             },
-            listen_host: "::1".to_string(),
+            listen_hosts: listen_hosts(settings, NodeRef::Keeper(this_keeper)),
+            listen_try: settings.listen_try,
             tcp_port: self.config.base_ports.keeper + this_keeper.0 as u16,
             server_id: this_keeper,
             log_storage_path: dir.join("coordination").join("log"),
@@ -665,14 +2967,711 @@ impl Deployment {
             coordination_settings: KeeperCoordinationSettings {
                 operation_timeout_ms: 10000,
                 session_timeout_ms: 30000,
-                raft_logs_level: LogLevel::Trace,
+                raft_logs_level: settings.log_level.clone(),
+                raft: settings.keeper_raft.clone(),
             },
-            raft_config: RaftServers { servers: raft_servers.clone() },
+            raft_config: RaftServers { servers: raft_servers },
+            four_letter_word_allow_list: four_letter_word_allow_list(settings),
+        }
+    }
+}
+
+/// Check that no two nodes of `deployments` bind the same address and port
+///
+/// This covers ports within a single deployment as well as across them, so
+/// that several deployments can be verified to be isolated before they are
+/// started. Nodes only conflict if they share a listen host, where a wildcard
+/// host such as `::` overlaps every address. Every conflict is reported.
+pub fn check_no_overlap(deployments: &[&Deployment]) -> Result<()> {
+    type Binding<'a> = (Vec<String>, &'a Utf8Path, PortKind, NodeRef);
+    let mut seen: BTreeMap<u16, Vec<Binding>> = BTreeMap::new();
+    let mut conflicts = vec![];
+    for d in deployments {
+        let Some(meta) = &d.meta else {
+            bail!(MISSING_META);
         };
-        let mut f = File::create(dir.join("keeper-config.xml"))?;
-        f.write_all(config.to_xml().as_bytes())?;
-        f.flush()?;
+        let path = d.config.path.as_path();
+        for (port, kind, node) in d.port_map()? {
+            let hosts: Vec<_> = listen_hosts(&meta.settings, node)
+                .iter()
+                .map(|host| normalize_host(host))
+                .collect();
+            let bindings = seen.entry(port).or_default();
+            for (other_hosts, other_path, other_kind, other_node) in
+                bindings.iter()
+            {
+                if hosts_overlap(&hosts, other_hosts) {
+                    conflicts.push(format!(
+                        "port {port}: {other_kind} of {other_node} in \
+                         {other_path} and {kind} of {node} in {path}"
+                    ));
+                }
+            }
+            bindings.push((hosts, path, kind, node));
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!("Overlapping ports:\n  {}", conflicts.join("\n  "));
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Put a listen host in a canonical form, so that e.g. `::1` and `[::1]`
+/// compare equal
+fn normalize_host(host: &str) -> String {
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    match bare.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_ascii_lowercase(),
+    }
+}
+
+/// Return true if a port bound on every one of `a` clashes with the same port
+/// bound on every one of `b`
+fn hosts_overlap(a: &[String], b: &[String]) -> bool {
+    let is_wildcard = |host: &String| {
+        host.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified())
+    };
+    a.iter().chain(b).any(is_wildcard) || a.iter().any(|host| b.contains(host))
+}
+
+/// Send `GET /ping` to a clickhouse server's http port and check for its
+/// `Ok.` reply
+async fn ping_server(addr: SocketAddr) -> std::io::Result<bool> {
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream
+        .write_all(
+            format!("GET /ping HTTP/1.0\r\nHost: {addr}\r\n\r\n").as_bytes(),
+        )
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    let status = response.split_whitespace().nth(1);
+    Ok(status == Some("200") && response.ends_with("Ok.\n"))
+}
+
+/// Return the IP address `node` listens on
+///
+/// With `loopback_per_node`, keepers get 127.1.x.y and servers 127.2.x.y, where
+/// x.y is the node id. These are only routable by default on Linux. Other
+/// platforms need loopback aliases configured for them.
+fn node_ip(settings: &ConfigSettings, node: NodeRef) -> IpAddr {
+    if !settings.loopback_per_node {
+        return IpAddr::V6(Ipv6Addr::LOCALHOST);
+    }
+    let (kind, id) = match node {
+        NodeRef::Keeper(id) => (1, id.0),
+        NodeRef::Server(id) => (2, id.0),
+    };
+    IpAddr::V4(Ipv4Addr::new(127, kind, (id >> 8) as u8, id as u8))
+}
+
+/// Return the addresses `node` listens on
+fn listen_hosts(settings: &ConfigSettings, node: NodeRef) -> Vec<String> {
+    if settings.listen_hosts.is_empty() {
+        vec![node_ip(settings, node).to_string()]
+    } else {
+        settings.listen_hosts.clone()
+    }
+}
+
+/// The four letter word allow list of every keeper
+///
+/// An empty list leaves ClickHouse's default in place. A custom list is
+/// extended with the words `KeeperClient` issues, so clickward keeps working.
+fn four_letter_word_allow_list(settings: &ConfigSettings) -> Vec<String> {
+    let mut words = settings.four_letter_word_allow_list.clone();
+    if !words.is_empty() {
+        for word in FOUR_LETTER_WORDS {
+            if !words.iter().any(|w| w == word) {
+                words.push(word.to_string());
+            }
+        }
+    }
+    words
+}
+
+/// The name of the config file inside a keeper's directory
+fn keeper_config_filename(format: ConfigFormat) -> String {
+    format!("keeper-config.{}", format.extension())
+}
+
+/// Run `f` on every item, spread across one thread per CPU
+///
+/// Items are split into one contiguous chunk per thread, and each thread
+/// stops at its first error. If several chunks fail, the error of the
+/// earliest chunk is returned, which isn't necessarily the first to occur.
+fn for_each_parallel<T: Sync>(
+    items: &[T],
+    f: impl Fn(&T) -> Result<()> + Sync,
+) -> Result<()> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    std::thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(|| chunk.iter().try_for_each(&f)))
+            .collect();
+        // Threads still running after an error are joined by the scope
+        handles.into_iter().try_for_each(|h| {
+            h.join().expect("config generation thread panicked")
+        })
+    })
+}
+
+/// A fresh secret, so that clusters under one path can't query each other
+fn random_secret() -> String {
+    use std::hash::BuildHasher;
+    let state = std::collections::hash_map::RandomState::new();
+    format!("{:016x}", state.hash_one(std::time::SystemTime::now()))
+}
+
+/// Render a command as the line one would type into a shell to run it
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A node's parsed config file, along with its format
+struct NodeConfig(ConfigFormat, ConfigNode);
+
+impl NodeConfig {
+    /// Return the node at `path`, a list of nested keys
+    fn node(&self, path: &[&str]) -> Result<&ConfigNode> {
+        let mut node = &self.1;
+        for key in path {
+            node = node.get(key).with_context(|| {
+                format!("No {} found in config", path.join("."))
+            })?;
+        }
+        Ok(node)
+    }
+
+    /// Return the scalar value at `path`
+    fn value(&self, path: &[&str]) -> Result<String> {
+        let value = self.node(path)?.as_value().with_context(|| {
+            format!("{} in config is not a value", path.join("."))
+        })?;
+        Ok(value.to_string())
+    }
+
+    fn parse<T: std::str::FromStr>(&self, path: &[&str]) -> Result<T> {
+        let value = self.value(path)?;
+        value
+            .parse()
+            .map_err(|_| anyhow!("Bad {} in config: {value}", path.join(".")))
+    }
+}
+
+/// Parse the scalar value of the child `key` of a config map
+fn parse_config_value<T: std::str::FromStr>(
+    node: &ConfigNode,
+    key: &str,
+) -> Result<T> {
+    let value = node
+        .get(key)
+        .and_then(|n| n.as_value())
+        .with_context(|| format!("No {key} found in config"))?;
+    value.parse().map_err(|_| anyhow!("Bad {key} in config: {value}"))
+}
+
+/// Read and parse the config file named `name` from a node's directory, in
+/// whichever format it was generated
+fn read_node_config(dir: &Utf8Path, name: &str) -> Result<NodeConfig> {
+    for format in [ConfigFormat::Xml, ConfigFormat::Yaml] {
+        let path = dir.join(format!("{name}.{}", format.extension()));
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let config = ConfigNode::parse(&contents, format)
+                    .map_err(|e| anyhow!("Bad config {path}: {e}"))?;
+                return Ok(NodeConfig(format, config));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(
+                    anyhow::Error::new(e).context(format!("Reading {path}"))
+                )
+            }
+        }
+    }
+    bail!("No {name} found in {dir}")
+}
+
+/// Return the current members among nodes that each list the members of the
+/// cluster in their config
+///
+/// Members are regenerated on every membership change, so they all list
+/// exactly the members. A removed node still lists the members as they were
+/// when it was removed, which the remaining members no longer agree with.
+fn current_members<T: Ord + Copy>(
+    listed: &BTreeMap<T, BTreeSet<T>>,
+    kind: &str,
+) -> Result<BTreeSet<T>> {
+    let agreed: BTreeSet<_> = listed
+        .values()
+        .filter(|ids| {
+            !ids.is_empty() && ids.iter().all(|id| listed.get(id) == Some(ids))
+        })
+        .collect();
+    let mut agreed = agreed.into_iter();
+    match (agreed.next(), agreed.next()) {
+        (Some(members), None) => Ok(members.clone()),
+        (None, _) => bail!("The {kind} configs don't agree on the members"),
+        (Some(_), Some(_)) => {
+            bail!("The {kind} configs describe more than one cluster")
+        }
+    }
+}
+
+/// A shard as listed in `remote_servers`
+struct RecoveredShard {
+    weight: Option<u32>,
+    ids: BTreeSet<ServerId>,
+}
+
+/// Find the shard topology that explains the macros of every server in
+/// `servers` and the current shards, as listed in `remote_servers`
+///
+/// A server's shard and `{replica}` macro never change, so the configs of
+/// removed servers count as well. If several shard counts fit, the smallest
+/// is used.
+fn recover_shard_topology(
+    servers: &BTreeMap<ServerId, NodeConfig>,
+    shards: &[RecoveredShard],
+) -> Result<ShardTopology> {
+    let macros = servers
+        .iter()
+        .map(|(&id, config)| {
+            Ok((
+                id,
+                config.parse::<u64>(&["macros", "shard"])?,
+                config.parse::<u64>(&["macros", "replica"])?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let max_id = servers.keys().last().map_or(1, |id| id.0);
+    for num_shards in 1..=max_id {
+        for replica_macros in
+            [ReplicaMacroScheme::Global, ReplicaMacroScheme::PerShard]
+        {
+            let mut topology = ShardTopology {
+                num_shards,
+                weights: BTreeMap::new(),
+                replica_macros,
+            };
+            let fits_macros = macros.iter().all(|&(id, shard, replica)| {
+                topology.shard_of(id) == shard
+                    && topology.replica_macro(id) == replica
+            });
+            if !fits_macros {
+                continue;
+            }
+            // Every listed shard must hold the servers of exactly one shard,
+            // and no two listed shards the same one
+            let mut fits_shards = true;
+            let mut seen = BTreeSet::new();
+            for shard in shards {
+                let numbers: BTreeSet<_> =
+                    shard.ids.iter().map(|&id| topology.shard_of(id)).collect();
+                match (numbers.first(), numbers.len()) {
+                    (Some(&number), 1) if seen.insert(number) => {
+                        if let Some(weight) = shard.weight {
+                            topology.weights.insert(number, weight);
+                        }
+                    }
+                    _ => fits_shards = false,
+                }
+            }
+            if fits_shards {
+                return Ok(topology);
+            }
+        }
+    }
+    bail!("The shards of the clickhouse servers don't match any topology")
+}
+
+/// The name of the config file inside a clickhouse server's directory
+fn clickhouse_config_filename(format: ConfigFormat) -> String {
+    format!("clickhouse-config.{}", format.extension())
+}
+
+/// Return true if the pid stored in `pidfile` refers to a live process
+fn pidfile_is_running(pidfile: &Utf8Path) -> bool {
+    let Ok(pid) = std::fs::read_to_string(pidfile) else {
+        return false;
+    };
+    pid_is_running(pid.trim_end())
+}
+
+/// Return true if `pid` refers to a live process
+///
+/// A killed process that hasn't been reaped by its parent yet still answers
+/// `kill -0`, so where `/proc` is available zombies are treated as exited.
+fn pid_is_running(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        Ok(stat) => {
+            // The state is the first field after the parenthesized command
+            // name
+            let state = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().next());
+            return state != Some("Z");
+        }
+        Err(_) if Utf8Path::new("/proc/self").exists() => return false,
+        Err(_) => {}
+    }
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Wait until none of `pids` is running
+fn wait_for_exit(pids: &[String]) -> Result<()> {
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    loop {
+        let running: Vec<_> =
+            pids.iter().filter(|pid| pid_is_running(pid)).collect();
+        if running.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            bail!("pids {running:?} still running after {STOP_TIMEOUT:?}");
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Return the pids of the direct children of `pid`, or `None` if the process
+/// tree isn't available via `/proc`
+fn child_pids(pid: &str) -> Option<Vec<String>> {
+    let entries = std::fs::read_dir("/proc").ok()?;
+    let mut children = vec![];
+    for entry in entries.flatten() {
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat"))
+        else {
+            continue;
+        };
+        // The parent pid is the second field after the parenthesized command
+        // name, which may itself contain spaces and parentheses
+        let Some((_, rest)) = stat.rsplit_once(')') else {
+            continue;
+        };
+        if rest.split_whitespace().nth(1) == Some(pid) {
+            children.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    Some(children)
+}
+
+/// Return the pids of live processes with an argument below `dir`, such as
+/// the config file of a node, or `None` if processes can't be listed via
+/// `/proc`
+fn pids_using_dir(dir: &Utf8Path) -> Option<Vec<String>> {
+    let entries = std::fs::read_dir("/proc").ok()?;
+    let mut pids = vec![];
+    for entry in entries.flatten() {
+        let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        let uses_dir = cmdline
+            .split(|&b| b == 0)
+            .filter_map(|arg| std::str::from_utf8(arg).ok())
+            .any(|arg| Utf8Path::new(arg).starts_with(dir));
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if uses_dir && pid_is_running(&pid) {
+            pids.push(pid);
+        }
+    }
+    Some(pids)
+}
+
+fn remove_dir_if_exists(dir: &Utf8Path) -> Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {dir}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove_file_if_exists(path: &Utf8Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {path}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(s: &str) -> Result<Vec<u64>, String> {
+        parse_id_set::<KeeperId>(s)
+            .map(|ids| ids.iter().map(|id| id.0).collect())
+    }
+
+    #[test]
+    fn stop_server_kills_watchdog_and_child() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let deployment = Deployment::new(
+            DeploymentConfig::new_with_default_ports(path, "test"),
+        );
+        let dir = deployment.server_dir(ServerId(1));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Stand in for the clickhouse watchdog: a process group leader that
+        // forks a child and waits on it
+        let child_pidfile = dir.join("child.pid");
+        let mut watchdog = Command::new("sh")
+            .arg("-c")
+            .arg(format!("sleep 60 & echo $! > {child_pidfile}; wait"))
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = watchdog.id().to_string();
+        std::fs::write(dir.join("clickhouse.pid"), format!("{pid}\n")).unwrap();
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        let child_pid = loop {
+            match std::fs::read_to_string(&child_pidfile) {
+                Ok(s) if s.ends_with('\n') => break s.trim_end().to_string(),
+                _ if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => panic!("watchdog never forked its child"),
+            }
+        };
+        assert!(pid_is_running(&pid));
+        assert!(pid_is_running(&child_pid));
+
+        deployment.stop_server(ServerId(1)).unwrap();
+        assert!(!pid_is_running(&pid));
+        assert!(!pid_is_running(&child_pid));
+        assert!(!dir.join("clickhouse.pid").exists());
+        watchdog.wait().unwrap();
+    }
+
+    #[test]
+    fn hosts_overlap_on_shared_or_wildcard_addresses() {
+        let hosts = |hosts: &[&str]| -> Vec<String> {
+            hosts.iter().map(|h| normalize_host(h)).collect()
+        };
+        let a = hosts(&["127.1.0.1", "[::1]"]);
+        assert!(hosts_overlap(&a, &hosts(&["::1"])));
+        assert!(hosts_overlap(&a, &hosts(&["0.0.0.0"])));
+        assert!(hosts_overlap(&hosts(&["::"]), &hosts(&["127.1.0.2"])));
+        assert!(!hosts_overlap(&a, &hosts(&["127.1.0.2"])));
+        assert!(hosts_overlap(
+            &hosts(&["Keeper.example.com"]),
+            &hosts(&["keeper.example.com"])
+        ));
+    }
+
+    #[test]
+    fn generate_config_writes_every_config_of_a_large_deployment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let mut deployment = Deployment::new(
+            DeploymentConfig::new_with_default_ports(path, "test"),
+        );
+        deployment.generate_config(3, 256).unwrap();
+
+        let format = ConfigFormat::default();
+        for id in 1..=3 {
+            let config = deployment
+                .keeper_dir(KeeperId(id))
+                .join(keeper_config_filename(format));
+            let contents = std::fs::read_to_string(&config).unwrap();
+            assert!(contents.contains("<keeper_server>"), "{config}");
+        }
+        for id in 1..=256 {
+            let config = deployment
+                .server_dir(ServerId(id))
+                .join(clickhouse_config_filename(format));
+            let contents = std::fs::read_to_string(&config).unwrap();
+            let tcp_port = deployment.native_port(ServerId(id));
+            assert!(
+                contents.contains(&format!("<tcp_port>{tcp_port}</tcp_port>")),
+                "{config}"
+            );
+        }
+    }
+
+    #[test]
+    fn recover_metadata_skips_removed_nodes_and_keeps_the_shards() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let mut deployment = Deployment::new(
+            DeploymentConfig::new_with_default_ports(path, "test"),
+        );
+        let settings = ConfigSettings {
+            shards: ShardTopology {
+                num_shards: 2,
+                weights: [(2, 5)].into(),
+                replica_macros: ReplicaMacroScheme::PerShard,
+            },
+            ..Default::default()
+        };
+        deployment.generate_config_with_settings(3, 4, settings).unwrap();
+        deployment.remove_server(ServerId(3)).unwrap();
+        deployment.remove_server(ServerId(2)).unwrap();
+        deployment.add_server_with_id(ServerId(2)).unwrap();
+        let expected = deployment.meta().clone().unwrap();
+
+        let dir = deployment.path();
+        std::fs::remove_file(dir.join(CLICKWARD_META_FILENAME)).unwrap();
+        let recovered = Deployment::recover_metadata(dir).unwrap();
+        assert!(recovered.recovered);
+        assert_eq!(recovered.keeper_ids, expected.keeper_ids);
+        assert_eq!(recovered.server_ids, expected.server_ids);
+        assert_eq!(recovered.max_server_id, ServerId(4));
+        assert_eq!(recovered.settings.shards, expected.settings.shards);
+        assert_eq!(recovered.settings.secret, expected.settings.secret);
+    }
+
+    // Timings depend on the machine, so this only runs when asked for, e.g.
+    // with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn generate_config_in_parallel_beats_a_sequential_loop() {
+        const RUNS: u32 = 5;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let deployment = Deployment::new(
+            DeploymentConfig::new_with_default_ports(path, "test"),
+        );
+        let settings = ConfigSettings::default();
+        let keeper_ids = (1..=3).map(KeeperId).collect();
+        let replica_ids = (1..=256).map(ServerId).collect();
+        let remote_servers =
+            deployment.build_remote_servers(&replica_ids, &settings);
+        let keepers =
+            deployment.build_keepers_for_replica(&keeper_ids, &settings);
+        let ids: Vec<_> = replica_ids.into_iter().collect();
+        let write = |&id: &ServerId| {
+            deployment.write_clickhouse_config(
+                id,
+                &remote_servers,
+                &keepers,
+                &settings,
+            )
+        };
+
+        // Take the fastest of several runs of each, to even out noise
+        let time = |f: &dyn Fn() -> Result<()>| {
+            (0..RUNS)
+                .map(|_| {
+                    let start = Instant::now();
+                    f().unwrap();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let sequential = time(&|| ids.iter().try_for_each(write));
+        let parallel = time(&|| for_each_parallel(&ids, write));
+        println!(
+            "256 replicas: sequential {sequential:?}, parallel {parallel:?}"
+        );
+        if std::thread::available_parallelism().map_or(1, |n| n.get()) > 1 {
+            assert!(parallel < sequential);
+        }
+    }
+
+    #[test]
+    fn pids_using_dir_finds_processes_by_argument() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let (dir, sibling) = (path.join("keeper-1"), path.join("keeper-10"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("keeper-config.xml");
+        std::fs::write(&config, "").unwrap();
+        let mut child = Command::new("tail")
+            .arg("-f")
+            .arg(&config)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id().to_string();
+        // The child may not have exec'd `tail` yet
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while pids_using_dir(&dir).is_some_and(|pids| pids.is_empty())
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if let Some(pids) = pids_using_dir(&dir) {
+            assert_eq!(pids, vec![pid]);
+            assert_eq!(pids_using_dir(&sibling), Some(vec![]));
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+        if let Some(pids) = pids_using_dir(&dir) {
+            assert!(pids.is_empty());
+        }
+    }
+
+    #[test]
+    fn check_member_tells_removed_nodes_from_unknown_ones() {
+        let mut meta = ClickwardMetadata::new(
+            [KeeperId(1), KeeperId(2), KeeperId(3)].into(),
+            [ServerId(1), ServerId(2)].into(),
+        );
+        meta.remove_keeper(KeeperId(2)).unwrap();
+        let check = |node| meta.check_member(node).map_err(|e| e.to_string());
+        assert_eq!(check(NodeRef::Keeper(KeeperId(1))), Ok(()));
+        assert_eq!(check(NodeRef::Server(ServerId(2))), Ok(()));
+        for node in [
+            NodeRef::Keeper(KeeperId(0)),
+            NodeRef::Keeper(KeeperId(4)),
+            NodeRef::Server(ServerId(3)),
+        ] {
+            assert!(matches!(
+                meta.check_member(node),
+                Err(ClickwardError::NeverExisted(n)) if n == node
+            ));
+        }
+        assert!(matches!(
+            meta.check_member(NodeRef::Keeper(KeeperId(2))),
+            Err(ClickwardError::AlreadyRemoved(_))
+        ));
+    }
+
+    #[test]
+    fn parse_id_set_accepts_ids_and_ranges() {
+        assert_eq!(ids("2-4,7"), Ok(vec![2, 3, 4, 7]));
+        assert_eq!(ids("5"), Ok(vec![5]));
+        assert_eq!(ids(" 1 - 2 , 9 "), Ok(vec![1, 2, 9]));
+        assert_eq!(ids("3-3"), Ok(vec![3]));
+    }
+
+    #[test]
+    fn parse_id_set_merges_overlaps() {
+        assert_eq!(ids("1-3,2-4,3"), Ok(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn parse_id_set_rejects_bad_input() {
+        for bad in ["4-2", "", "1,,2", "1,", "-3", "1-", "x", "1-x", "-1"] {
+            assert!(ids(bad).is_err(), "{bad:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn parse_id_set_rejects_huge_ranges() {
+        assert!(ids("1-4000000000").is_err());
+        assert!(ids(&format!("1-{MAX_ID_RANGE_LEN}")).is_ok());
+        assert!(ids(&format!("1-{}", MAX_ID_RANGE_LEN + 1)).is_err());
     }
 }
@@ -0,0 +1,66 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::net::SocketAddr;
+use std::process::{ExitStatus, Stdio};
+use thiserror::Error;
+use tokio::process::Command;
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("clickhouse client failed ({status}): {stderr}")]
+    BinaryFailed { status: ExitStatus, stderr: String },
+}
+
+/// A client for running SQL against clickhouse server instances
+///
+/// This shells out to `clickhouse client` and talks to the server's native
+/// TCP port.
+#[derive(Debug, Clone)]
+pub struct ServerClient {
+    addr: SocketAddr,
+}
+
+impl ServerClient {
+    pub fn new(addr: SocketAddr) -> ServerClient {
+        ServerClient { addr }
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Run `sql` and return whatever the server printed
+    ///
+    /// `sql` may contain several statements separated by `;`.
+    pub async fn query(&self, sql: &str) -> Result<String, ServerError> {
+        let output = Command::new("clickhouse")
+            .arg("client")
+            .arg("--host")
+            .arg(self.addr.ip().to_string())
+            .arg("--port")
+            .arg(self.addr.port().to_string())
+            .arg("--multiquery")
+            .arg("--query")
+            .arg(sql)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(ServerError::BinaryFailed {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr)
+                    .trim()
+                    .to_string(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::config::{validate_element_name, ShardTopology};
+use crate::{BasePorts, ConfigSettings};
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+/// A declarative description of a whole deployment
+///
+/// Specs are read from JSON or TOML files, which allows deployments to be
+/// reproduced from version control rather than from a long list of flags.
+///
+/// The cluster name, ports, shard topology and listen hosts may be given at
+/// the top level of a spec. They take precedence over the same fields in
+/// `settings`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeploymentSpec {
+    pub num_keepers: u64,
+    pub num_replicas: u64,
+
+    /// The name of the clickhouse cluster. Defaults to the name used by the
+    /// caller if unset.
+    #[serde(default)]
+    pub cluster_name: Option<String>,
+
+    /// Defaults to `DEFAULT_BASE_PORTS` if unset
+    #[serde(default)]
+    pub base_ports: Option<BasePorts>,
+
+    /// How clickhouse servers are split into shards
+    #[serde(default)]
+    pub shards: Option<ShardTopology>,
+
+    /// Addresses every node listens on
+    #[serde(default)]
+    pub listen_hosts: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub settings: ConfigSettings,
+}
+
+impl DeploymentSpec {
+    pub fn new(num_keepers: u64, num_replicas: u64) -> DeploymentSpec {
+        DeploymentSpec {
+            num_keepers,
+            num_replicas,
+            cluster_name: None,
+            base_ports: None,
+            shards: None,
+            listen_hosts: None,
+            settings: ConfigSettings::default(),
+        }
+    }
+
+    /// Load a spec from a file, parsed as TOML if the file has a `.toml`
+    /// extension and as JSON otherwise
+    pub fn load(path: &Utf8Path) -> Result<DeploymentSpec> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {path}"))?;
+        let spec = if path.extension() == Some("toml") {
+            toml::from_str(&contents)
+                .with_context(|| format!("invalid spec in {path}"))?
+        } else {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("invalid spec in {path}"))?
+        };
+        Ok(spec)
+    }
+
+    /// Move the top-level fields into `settings`, where the rest of clickward
+    /// looks for them
+    pub fn normalize(&mut self) {
+        if let Some(cluster_name) = self.cluster_name.take() {
+            self.settings.cluster_name = Some(cluster_name);
+        }
+        if let Some(base_ports) = self.base_ports.take() {
+            self.settings.base_ports = Some(base_ports);
+        }
+        if let Some(shards) = self.shards.take() {
+            self.settings.shards = shards;
+        }
+        if let Some(listen_hosts) = self.listen_hosts.take() {
+            self.settings.listen_hosts = listen_hosts;
+        }
+    }
+
+    /// Check that the spec describes a sensible deployment
+    ///
+    /// On top of `validate_layout`, this requires an odd number of keepers.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_layout()?;
+        if self.num_keepers.is_multiple_of(2) {
+            bail!(
+                "A keeper cluster needs an odd number of nodes to tolerate \
+                 failures, got {}",
+                self.num_keepers
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that configs can be generated for the spec, e.g. that none of
+    /// its ports overlap
+    pub fn validate_layout(&self) -> Result<()> {
+        let mut spec = self.clone();
+        spec.normalize();
+        spec.validate_settings()
+    }
+
+    fn validate_settings(&self) -> Result<()> {
+        if self.num_keepers == 0 || self.num_replicas == 0 {
+            bail!("A deployment needs at least one keeper and one replica");
+        }
+        if let Some(cluster_name) = &self.settings.cluster_name {
+            validate_element_name("cluster", cluster_name)
+                .map_err(anyhow::Error::msg)?;
+        }
+        self.settings.storage.validate().map_err(anyhow::Error::msg)?;
+        self.settings.shards.validate().map_err(anyhow::Error::msg)?;
+        if let Some(disk) =
+            self.settings.storage.disks.iter().find(|d| d.path.is_absolute())
+        {
+            bail!("Disk paths must be relative: {}", disk.path);
+        }
+
+        // Every port range is `base + 1..=base + num_nodes`
+        let ports = self
+            .settings
+            .base_ports
+            .clone()
+            .unwrap_or(crate::DEFAULT_BASE_PORTS);
+        let ranges = [
+            ("keeper", ports.keeper, self.num_keepers),
+            ("raft", ports.raft, self.num_keepers),
+            ("clickhouse_tcp", ports.clickhouse_tcp, self.num_replicas),
+            ("clickhouse_http", ports.clickhouse_http, self.num_replicas),
+            (
+                "clickhouse_interserver_http",
+                ports.clickhouse_interserver_http,
+                self.num_replicas,
+            ),
+            (
+                "prometheus",
+                ports.prometheus,
+                if self.settings.prometheus_endpoint.is_some() {
+                    self.num_replicas
+                } else {
+                    0
+                },
+            ),
+        ];
+        for (name, base, n) in ranges {
+            if u64::from(base) + n > u64::from(u16::MAX) {
+                bail!("{name} ports starting at {base} exceed the port range");
+            }
+        }
+        for (i, (name_a, base_a, n_a)) in ranges.iter().enumerate() {
+            for (name_b, base_b, n_b) in &ranges[i + 1..] {
+                let (a, b) = (u64::from(*base_a), u64::from(*base_b));
+                if a < b + n_b && b < a + n_a {
+                    bail!(
+                        "{name_a} ports ({}-{}) overlap {name_b} ports \
+                         ({}-{})",
+                        a + 1,
+                        a + n_a,
+                        b + 1,
+                        b + n_b
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}